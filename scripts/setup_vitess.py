@@ -10,6 +10,11 @@ CREATE TABLE users (
   username VARCHAR(255),
   email VARCHAR(255)
 );
+
+CREATE TABLE replicator_watermarks (
+  shard VARCHAR(32) PRIMARY KEY,
+  watermark VARCHAR(128) NOT NULL
+);
 """
 
 subprocess.check_call(
@@ -33,6 +38,14 @@ VSCHEMA = {
           "name": "xxhash"
         }
       ]
+    },
+    "replicator_watermarks": {
+      "column_vindexes": [
+        {
+          "column": "shard",
+          "name": "xxhash"
+        }
+      ]
     }
   }
 }
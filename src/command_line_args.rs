@@ -1,4 +1,12 @@
-use clap::{Parser, arg, command};
+use std::path::PathBuf;
+
+use clap::{ArgAction, Parser, ValueEnum, arg, command};
+use jiff::tz::TimeZone;
+
+use crate::{
+    vitess_schema::TableName,
+    vitess_table_filter::{TableRule, parse_table_rule},
+};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -12,6 +20,270 @@ pub(crate) struct Args {
     #[arg(long)]
     pub(crate) vtgate_endpoint: String,
 
+    /// Table to replicate, either a name, `/regex/` or glob like `orders_*`. Exact names can
+    /// narrow the stream with `table=SELECT col1, col2 FROM table WHERE ...`, the snapshot runs
+    /// the `WHERE` as plain MySQL so `in_keyrange(...)` only works without `--initial-load snapshot`
+    #[arg(long, value_parser = parse_table_rule)]
+    pub(crate) tables: Vec<TableRule>,
+
+    #[arg(long)]
+    pub(crate) checkpoint_file: Option<PathBuf>,
+
+    #[arg(long, value_enum, default_value_t = InitialLoadMode::None)]
+    pub(crate) initial_load: InitialLoadMode,
+
+    /// Rows read per snapshot query, at least 1
+    #[arg(long, default_value_t = 1000, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub(crate) snapshot_chunk_size: usize,
+
+    /// Table with the columns `(shard, watermark)` that snapshot watermarks are written to
+    #[arg(long, default_value = "replicator_watermarks")]
+    pub(crate) snapshot_signal_table: String,
+
+    #[arg(long, value_enum, default_value_t = SourceTabletType::Primary)]
+    pub(crate) tablet_type: SourceTabletType,
+
+    /// Comma separated cells to stream from, defaults to the cell of the vtgate
+    #[arg(long)]
+    pub(crate) cells: Option<String>,
+
+    /// Defaults to vtgate's own preference, which is `prefer-local`
+    #[arg(long, value_enum)]
+    pub(crate) cell_preference: Option<CellPreference>,
+
+    /// Seconds between heartbeats on an idle stream, 0 disables them
+    #[arg(long, default_value_t = 0)]
+    pub(crate) heartbeat_interval_secs: u32,
+
+    #[arg(long)]
+    pub(crate) stream_keyspace_heartbeats: bool,
+
+    /// Hold back events of shards that are ahead so all shards stream at a similar position
+    #[arg(long)]
+    pub(crate) minimize_skew: bool,
+
+    /// Stop at reshard journals and reopen the stream from the new shards. With `false` vtgate
+    /// switches shards on its own and never sends the journal, the replicator then follows the
+    /// vgtid events alone and logs no migration
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    pub(crate) stop_on_reshard: bool,
+
+    /// Give up after this many consecutive failed vstream reconnects, retries forever if unset
+    #[arg(long)]
+    pub(crate) reconnect_max_retries: Option<u32>,
+
+    #[arg(long, default_value_t = 500)]
+    pub(crate) reconnect_initial_backoff_ms: u64,
+
+    #[arg(long, default_value_t = 30000)]
+    pub(crate) reconnect_max_backoff_ms: u64,
+
+    /// Emit a marker with the row count after the rows of every transaction
     #[arg(long)]
-    pub(crate) tables: Vec<String>,
+    pub(crate) transaction_markers: bool,
+
+    /// Number of messages buffered between the listener, converter and sink before the
+    /// vstream read waits for the sink to catch up
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u32).range(1..))]
+    pub(crate) channel_capacity: u32,
+
+    /// How `DECIMAL` and 64 bit integer columns are written to json
+    #[arg(long, value_enum, default_value_t = NumericMode::Number)]
+    pub(crate) numeric_mode: NumericMode,
+
+    /// How `BINARY`, `VARBINARY`, `BLOB` and geometry values are written to json strings
+    #[arg(long, value_enum, default_value_t = BinaryEncoding::Base64)]
+    pub(crate) binary_encoding: BinaryEncoding,
+
+    /// Whether `JSON` columns are embedded as json values or written as json text strings
+    #[arg(long, value_enum, default_value_t = JsonColumnMode::Nested)]
+    pub(crate) json_column_mode: JsonColumnMode,
+
+    /// How `DATE`, `DATETIME`, `TIMESTAMP` and `TIME` values are written to json
+    #[arg(long, value_enum, default_value_t = TemporalMode::Iso8601)]
+    pub(crate) temporal_mode: TemporalMode,
+
+    /// Time zone `DATETIME` values were written in, as an IANA name like `Europe/Berlin`
+    #[arg(long, default_value = "UTC", value_parser = parse_time_zone)]
+    pub(crate) source_time_zone: TimeZone,
+
+    /// What to do with dates that are zero or have a zero month or day, like `0000-00-00`
+    #[arg(long, value_enum, default_value_t = ZeroDatePolicy::Null)]
+    pub(crate) zero_date_policy: ZeroDatePolicy,
+
+    /// Shape of the records written for row changes
+    #[arg(long, value_enum, default_value_t = RowEnvelope::Flat)]
+    pub(crate) row_envelope: RowEnvelope,
+
+    /// Wrap Debezium records and keys as `{"schema": ..., "payload": ...}` like the Kafka Connect
+    /// json converter with schemas enabled. Requires `--json-column-mode string`.
+    #[arg(long)]
+    pub(crate) debezium_schema: bool,
+
+    /// Only write the columns an update changed, plus the primary key, in its before and after
+    /// rows. Deletes always carry the whole row.
+    #[arg(long)]
+    pub(crate) changed_columns_only: bool,
+
+    /// Write an update that changes the primary key as a delete of the old key, a tombstone
+    /// without a value for it and an insert of the new key, so key based sinks drop the old key
+    #[arg(long)]
+    pub(crate) split_primary_key_updates: bool,
+
+    /// What to do with a row that can't be converted to json
+    #[arg(long, value_enum, default_value_t = RowErrorPolicy::Fail)]
+    pub(crate) row_error_policy: RowErrorPolicy,
+
+    /// Overrides `--row-error-policy` for a single table as `table=policy`
+    #[arg(long, value_parser = parse_table_row_error_policy)]
+    pub(crate) table_row_error_policy: Vec<(TableName, RowErrorPolicy)>,
+
+    #[arg(long, value_enum, default_value_t = SinkType::Console)]
+    pub(crate) sink: SinkType,
+
+    #[arg(long, default_value = "localhost:9093")]
+    pub(crate) kafka_brokers: String,
+
+    /// Topic name for each table, `{keyspace}` and `{table}` are replaced per record
+    #[arg(long, default_value = "{keyspace}.{table}")]
+    pub(crate) kafka_topic_template: String,
+
+    /// Topic for DDL statements, `{keyspace}` is replaced per statement
+    #[arg(long, default_value = "{keyspace}.schema_changes")]
+    pub(crate) kafka_schema_change_topic_template: String,
+
+    /// Topic for transaction markers, `{keyspace}` is replaced per marker
+    #[arg(long, default_value = "{keyspace}.transactions")]
+    pub(crate) kafka_transaction_topic_template: String,
+
+    /// Topic for rows that failed to convert, `{keyspace}` and `{table}` are replaced per record
+    #[arg(long, default_value = "{keyspace}.dead_letters")]
+    pub(crate) kafka_dead_letter_topic_template: String,
+
+    #[arg(long, default_value_t = 1000)]
+    pub(crate) kafka_checkpoint_interval_ms: u64,
+
+    #[arg(long, default_value_t = 30000)]
+    pub(crate) kafka_flush_timeout_ms: u64,
+
+    /// Extra librdkafka producer properties as `key=value`
+    #[arg(long, value_parser = parse_key_value)]
+    pub(crate) kafka_property: Vec<(String, String)>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InitialLoadMode {
+    /// Only stream changes made after the replicator starts
+    None,
+    /// Backfill every table with chunked primary key ordered queries interleaved with the
+    /// stream using watermarks
+    Snapshot,
+    /// Let vstream copy every table consistently before it starts streaming changes
+    VstreamCopy,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SourceTabletType {
+    Primary,
+    Replica,
+    Rdonly,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CellPreference {
+    /// Prefer tablets in the cell of the vtgate, falling back to the other `--cells`
+    PreferLocal,
+    /// Only stream from tablets in `--cells`
+    OnlySpecified,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NumericMode {
+    /// Json number with every digit mysql returned, consumers that parse numbers as doubles
+    /// lose precision
+    Number,
+    /// Json string with the value as mysql formats it
+    String,
+    /// `DECIMAL` as `{"unscaled": 12345, "scale": 2, "precision": 10}`, integers as numbers
+    /// except `BIGINT UNSIGNED`, which is a string since it can exceed a signed 64 bit integer
+    ScaledInteger,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BinaryEncoding {
+    Base64,
+    /// Lowercase hex without a prefix
+    Hex,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum JsonColumnMode {
+    /// Embed the document, a json `null` document can't be told apart from sql `NULL`
+    Nested,
+    /// Write the document as a string for sinks that need a flat schema, a json `null` document
+    /// is written as `"null"`
+    String,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TemporalMode {
+    /// `2024-01-02`, `2024-01-02T03:04:05.123Z` in UTC and durations like `PT3H4M5S` for `TIME`
+    #[value(name = "iso8601")]
+    Iso8601,
+    /// Milliseconds since the epoch, or of the duration for `TIME`
+    EpochMillis,
+    /// Microseconds since the epoch, or of the duration for `TIME`
+    EpochMicros,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ZeroDatePolicy {
+    /// Write `null`
+    Null,
+    /// Write the mysql string unchanged
+    Raw,
+    /// Treat the row as failed, see `--row-error-policy`
+    Fail,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RowEnvelope {
+    /// The columns of the new row, or of the deleted one, next to an `op` letter. Updates carry
+    /// the old and the new row under `before` and `after`.
+    Flat,
+    /// `before`, `after`, `source`, `op` and `ts_ms` like the Debezium connectors write
+    Debezium,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RowErrorPolicy {
+    /// Stop the replicator
+    Fail,
+    /// Log the error and drop the row
+    Skip,
+    /// Send the raw row with the error to the dead-letter topic so it can be replayed
+    DeadLetter,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SinkType {
+    Console,
+    Kafka,
+}
+
+fn parse_key_value(value: &str) -> Result<(String, String), String> {
+    value
+        .split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected `key=value` but got `{}`", value))
+}
+
+fn parse_table_row_error_policy(value: &str) -> Result<(TableName, RowErrorPolicy), String> {
+    let (table, policy) = parse_key_value(value)?;
+    let policy = RowErrorPolicy::from_str(&policy, true)?;
+    Ok((table.into(), policy))
+}
+
+fn parse_time_zone(value: &str) -> Result<TimeZone, String> {
+    TimeZone::get(value).map_err(|e| format!("unknown time zone `{}`: {}", value, e))
 }
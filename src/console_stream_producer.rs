@@ -1,7 +1,12 @@
 use std::{
     error::Error,
     fmt::{self, Display, Formatter},
-    sync::mpsc::{Receiver, RecvError},
+    sync::mpsc::Receiver,
+};
+
+use crate::{
+    table_row_change_json_converter::JsonStreamMessage,
+    vgtid_checkpoint_store::{CheckpointStoreError, VGtidCheckpointStore},
 };
 
 #[derive(Debug)]
@@ -19,26 +24,53 @@ impl Display for ConsoleStreamProducerError {
 impl Error for ConsoleStreamProducerError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match &self.kind {
-            ConsoleStreamProducerErrorKind::RecvFailed(e) => Some(e),
+            ConsoleStreamProducerErrorKind::CheckpointFailed(e) => Some(e),
         }
     }
 }
 
 #[derive(Debug)]
 pub enum ConsoleStreamProducerErrorKind {
-    RecvFailed(RecvError),
+    CheckpointFailed(CheckpointStoreError),
 }
 
 pub(crate) fn run_console_stream_producer(
-    incoming_json: Receiver<serde_json::Value>,
+    incoming_json: Receiver<JsonStreamMessage>,
+    mut checkpoint_store: Box<dyn VGtidCheckpointStore>,
 ) -> Result<(), ConsoleStreamProducerError> {
     loop {
-        let json = incoming_json
-            .recv()
-            .map_err(|e| ConsoleStreamProducerError {
-                kind: ConsoleStreamProducerErrorKind::RecvFailed(e),
-            })?;
+        // Checkpoints are saved as they arrive, so there is nothing left to flush once the
+        // converter hangs up
+        let Ok(message) = incoming_json.recv() else {
+            return Ok(());
+        };
 
-        log::info!("Row Json -> {}", json);
+        match message {
+            JsonStreamMessage::Row(record) => match record.value {
+                Some(value) => log::info!("Row Json -> {}", value),
+                None => log::info!(
+                    "Tombstone for key -> {}",
+                    record.key.unwrap_or(serde_json::Value::Null)
+                ),
+            },
+            JsonStreamMessage::DeadLetter(record) => {
+                log::warn!("Dead Letter Json -> {}", record.value)
+            }
+            JsonStreamMessage::SchemaChange(record) => {
+                log::info!("Schema Change Json -> {}", record.value)
+            }
+            JsonStreamMessage::TransactionCommitted(record) => {
+                log::info!("Transaction Json -> {}", record.value)
+            }
+            JsonStreamMessage::Checkpoint(vgtid) => {
+                // Everything before the checkpoint has been written to the console already
+                checkpoint_store
+                    .save(&vgtid)
+                    .map_err(|e| ConsoleStreamProducerError {
+                        kind: ConsoleStreamProducerErrorKind::CheckpointFailed(e),
+                    })?;
+                log::debug!("Saved checkpoint {:?}", vgtid);
+            }
+        }
     }
 }
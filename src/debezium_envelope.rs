@@ -0,0 +1,603 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{Map, json};
+
+use crate::{
+    command_line_args::{BinaryEncoding, NumericMode, TemporalMode},
+    replication_row_event::{ReplicationRowEvent, ReplicationRowEventEnvelope},
+    table_row_deserializer::ValueConversionOptions,
+    vitess_grpc::query::{Field, MySqlFlag, Type},
+    vitess_schema::{FieldName, VitessSchema},
+};
+
+const CONNECTOR: &str = "vitess";
+const SOURCE_SCHEMA_NAME: &str = "io.debezium.connector.vitess.Source";
+
+/// Change event in the Debezium envelope, `before` and `after` are the rows the event has
+/// converted with [`crate::table_row_deserializer::transform_value_to_json`]
+pub(crate) fn debezium_envelope(
+    envelope: &ReplicationRowEventEnvelope,
+    before: Option<Map<String, serde_json::Value>>,
+    after: Option<Map<String, serde_json::Value>>,
+    schema: &VitessSchema,
+    options: &ValueConversionOptions,
+    include_schema: bool,
+) -> serde_json::Value {
+    let snapshot = matches!(envelope.event, ReplicationRowEvent::SnapshotRead(_));
+    let payload = json!({
+        "before": before,
+        "after": after,
+        "source": {
+            "connector": CONNECTOR,
+            "keyspace": envelope.keyspace.to_string(),
+            "shard": envelope.transaction.shard,
+            "table": envelope.table.to_string(),
+            "gtid": envelope.transaction.id,
+            "ts_ms": envelope.transaction.timestamp * 1000,
+            "snapshot": snapshot.to_string(),
+        },
+        "op": debezium_op(&envelope.event),
+        // When the replicator processed the event, `source.ts_ms` is when mysql committed it
+        "ts_ms": SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default(),
+    });
+    if !include_schema {
+        return payload;
+    }
+
+    let record_name = record_name(envelope);
+    let row_schema = |field_name| {
+        json!({
+            "type": "struct",
+            "name": format!("{}.Value", record_name),
+            "optional": true,
+            "field": field_name,
+            "fields": schema
+                .schema
+                .iter()
+                .map(|(column_name, field)| column_schema(column_name, field, options))
+                .collect::<Vec<_>>(),
+        })
+    };
+    json!({
+        "schema": {
+            "type": "struct",
+            "name": format!("{}.Envelope", record_name),
+            "optional": false,
+            "fields": [
+                row_schema("before"),
+                row_schema("after"),
+                source_schema(),
+                { "type": "string", "optional": false, "field": "op" },
+                { "type": "int64", "optional": true, "field": "ts_ms" },
+            ],
+        },
+        "payload": payload,
+    })
+}
+
+/// Record key with the primary key columns, wrapped with its schema like the value
+pub(crate) fn debezium_key(
+    envelope: &ReplicationRowEventEnvelope,
+    key: serde_json::Value,
+    schema: &VitessSchema,
+    options: &ValueConversionOptions,
+    include_schema: bool,
+) -> serde_json::Value {
+    if !include_schema {
+        return key;
+    }
+    json!({
+        "schema": {
+            "type": "struct",
+            "name": format!("{}.Key", record_name(envelope)),
+            "optional": false,
+            "fields": schema
+                .primary_key_fields()
+                .into_iter()
+                .map(|(_, column_name, field)| column_schema(column_name, field, options))
+                .collect::<Vec<_>>(),
+        },
+        "payload": key,
+    })
+}
+
+fn debezium_op(row_event: &ReplicationRowEvent) -> &'static str {
+    match row_event {
+        ReplicationRowEvent::Insert(_) => "c",
+        ReplicationRowEvent::SnapshotRead(_) => "r",
+        ReplicationRowEvent::Update { .. } => "u",
+        ReplicationRowEvent::Delete(_) => "d",
+    }
+}
+
+// Debezium names records after the logical server, the keyspace is ours
+fn record_name(envelope: &ReplicationRowEventEnvelope) -> String {
+    format!("{}.{}", envelope.keyspace, envelope.table)
+}
+
+fn source_schema() -> serde_json::Value {
+    let string_field = |field_name, optional| json!({ "type": "string", "optional": optional, "field": field_name });
+    json!({
+        "type": "struct",
+        "name": SOURCE_SCHEMA_NAME,
+        "optional": false,
+        "field": "source",
+        "fields": [
+            string_field("connector", false),
+            string_field("keyspace", false),
+            string_field("shard", false),
+            string_field("table", false),
+            string_field("gtid", false),
+            { "type": "int64", "optional": false, "field": "ts_ms" },
+            string_field("snapshot", true),
+        ],
+    })
+}
+
+fn column_schema(
+    column_name: &FieldName,
+    field: &Field,
+    options: &ValueConversionOptions,
+) -> serde_json::Value {
+    let mut column = column_type_schema(field, options);
+    column["optional"] = json!(field.flags & MySqlFlag::NotNullFlag as u32 == 0);
+    column["field"] = json!(column_name.to_string());
+    column
+}
+
+// Kafka Connect type of the json `transform_value_to_json` writes for the column, with the
+// Debezium logical type name where one describes the value
+fn column_type_schema(field: &Field, options: &ValueConversionOptions) -> serde_json::Value {
+    match field.r#type() {
+        Type::Int8 | Type::Uint8 | Type::Int16 => json!({ "type": "int16" }),
+        Type::Uint16 | Type::Int24 | Type::Uint24 | Type::Int32 => json!({ "type": "int32" }),
+        Type::Year => json!({ "type": "int32", "name": "io.debezium.time.Year" }),
+        Type::Int64 | Type::Uint64 if options.numeric_mode == NumericMode::String => {
+            json!({ "type": "string" })
+        }
+        Type::Uint64 if options.numeric_mode == NumericMode::ScaledInteger => {
+            json!({ "type": "string" })
+        }
+        // Numbers past int64, the json converter reads json numbers into decimals
+        Type::Uint64 | Type::Bit => json!({
+            "type": "bytes",
+            "name": "org.apache.kafka.connect.data.Decimal",
+            "version": 1,
+            "parameters": { "scale": "0" },
+        }),
+        Type::Uint32 | Type::Int64 => json!({ "type": "int64" }),
+        Type::Float32 => json!({ "type": "float" }),
+        Type::Float64 => json!({ "type": "double" }),
+        Type::Decimal => match options.numeric_mode {
+            NumericMode::Number => json!({ "type": "double" }),
+            NumericMode::String => json!({ "type": "string" }),
+            NumericMode::ScaledInteger => json!({
+                "type": "struct",
+                "fields": [
+                    { "type": "int64", "optional": false, "field": "unscaled" },
+                    { "type": "int32", "optional": false, "field": "scale" },
+                    { "type": "int32", "optional": false, "field": "precision" },
+                ],
+            }),
+        },
+        Type::Enum => json!({ "type": "string", "name": "io.debezium.data.Enum" }),
+        Type::Set => json!({ "type": "array", "items": { "type": "string" } }),
+        Type::Varbinary | Type::Binary | Type::Blob => {
+            json!({ "type": binary_type(options.binary_encoding) })
+        }
+        Type::Date if options.temporal_mode == TemporalMode::Iso8601 => {
+            json!({ "type": "string", "name": "io.debezium.time.IsoDate" })
+        }
+        Type::Time => match options.temporal_mode {
+            TemporalMode::Iso8601 => {
+                json!({ "type": "string", "name": "io.debezium.time.Interval" })
+            }
+            TemporalMode::EpochMillis => json!({ "type": "int64" }),
+            TemporalMode::EpochMicros => {
+                json!({ "type": "int64", "name": "io.debezium.time.MicroDuration" })
+            }
+        },
+        Type::Date | Type::Datetime | Type::Timestamp => match options.temporal_mode {
+            TemporalMode::Iso8601 => {
+                json!({ "type": "string", "name": "io.debezium.time.ZonedTimestamp" })
+            }
+            TemporalMode::EpochMillis => {
+                json!({ "type": "int64", "name": "io.debezium.time.Timestamp" })
+            }
+            TemporalMode::EpochMicros => {
+                json!({ "type": "int64", "name": "io.debezium.time.MicroTimestamp" })
+            }
+        },
+        Type::Json => json!({ "type": "string", "name": "io.debezium.data.Json" }),
+        Type::Geometry => json!({
+            "type": "struct",
+            "name": "io.debezium.data.geometry.Geometry",
+            "fields": [
+                { "type": binary_type(options.binary_encoding), "optional": false, "field": "wkb" },
+                { "type": "int32", "optional": true, "field": "srid" },
+            ],
+        }),
+        Type::Vector => json!({ "type": "array", "items": { "type": "float" } }),
+        _ => json!({ "type": "string" }),
+    }
+}
+
+// The json converter reads base64 strings as bytes
+fn binary_type(encoding: BinaryEncoding) -> &'static str {
+    match encoding {
+        BinaryEncoding::Base64 => "bytes",
+        BinaryEncoding::Hex => "string",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jiff::tz::TimeZone;
+
+    use super::*;
+    use crate::{
+        command_line_args::{JsonColumnMode, ZeroDatePolicy},
+        replication_row_event::TransactionContext,
+        vitess_grpc::query::Row,
+    };
+
+    fn options() -> ValueConversionOptions {
+        ValueConversionOptions {
+            numeric_mode: NumericMode::Number,
+            binary_encoding: BinaryEncoding::Base64,
+            json_column_mode: JsonColumnMode::String,
+            temporal_mode: TemporalMode::Iso8601,
+            source_time_zone: TimeZone::UTC,
+            zero_date_policy: ZeroDatePolicy::Null,
+        }
+    }
+
+    fn field(column_type: Type) -> Field {
+        Field {
+            name: "value".to_string(),
+            r#type: column_type.into(),
+            ..Default::default()
+        }
+    }
+
+    fn type_schema(column_type: Type, options: &ValueConversionOptions) -> serde_json::Value {
+        column_type_schema(&field(column_type), options)
+    }
+
+    fn envelope(event: ReplicationRowEvent) -> ReplicationRowEventEnvelope {
+        ReplicationRowEventEnvelope {
+            keyspace: "commerce".to_string().into(),
+            table: "orders".to_string().into(),
+            event,
+            transaction: TransactionContext {
+                id: "uuid:42".to_string(),
+                shard: "-80".to_string(),
+                timestamp: 1_700_000_000,
+                sequence: 0,
+            },
+            schema_version: 0,
+        }
+    }
+
+    fn schema() -> VitessSchema {
+        VitessSchema {
+            primary_keys: vec!["id".to_string()],
+            ..VitessSchema::from_field_event(
+                "orders".to_string().into(),
+                &[
+                    Field {
+                        name: "id".to_string(),
+                        flags: MySqlFlag::NotNullFlag as u32,
+                        ..field(Type::Int64)
+                    },
+                    Field {
+                        name: "status".to_string(),
+                        ..field(Type::Varchar)
+                    },
+                ],
+                None,
+            )
+        }
+    }
+
+    fn row_map(value: serde_json::Value) -> Option<Map<String, serde_json::Value>> {
+        match value {
+            serde_json::Value::Object(map) => Some(map),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn maps_events_to_ops() {
+        let row = Row::default;
+        assert_eq!(debezium_op(&ReplicationRowEvent::Insert(row())), "c");
+        assert_eq!(debezium_op(&ReplicationRowEvent::SnapshotRead(row())), "r");
+        assert_eq!(
+            debezium_op(&ReplicationRowEvent::Update {
+                before: row(),
+                after: row()
+            }),
+            "u"
+        );
+        assert_eq!(debezium_op(&ReplicationRowEvent::Delete(row())), "d");
+    }
+
+    #[test]
+    fn declares_integer_types() {
+        let number = options();
+        assert_eq!(type_schema(Type::Int8, &number), json!({ "type": "int16" }));
+        assert_eq!(
+            type_schema(Type::Uint8, &number),
+            json!({ "type": "int16" })
+        );
+        assert_eq!(
+            type_schema(Type::Uint16, &number),
+            json!({ "type": "int32" })
+        );
+        assert_eq!(
+            type_schema(Type::Int32, &number),
+            json!({ "type": "int32" })
+        );
+        assert_eq!(
+            type_schema(Type::Uint32, &number),
+            json!({ "type": "int64" })
+        );
+        assert_eq!(
+            type_schema(Type::Int64, &number),
+            json!({ "type": "int64" })
+        );
+        assert_eq!(
+            type_schema(Type::Year, &number),
+            json!({ "type": "int32", "name": "io.debezium.time.Year" })
+        );
+
+        // Unsigned bigints and bits can exceed int64
+        let decimal = json!({
+            "type": "bytes",
+            "name": "org.apache.kafka.connect.data.Decimal",
+            "version": 1,
+            "parameters": { "scale": "0" },
+        });
+        assert_eq!(type_schema(Type::Uint64, &number), decimal);
+        assert_eq!(type_schema(Type::Bit, &number), decimal);
+
+        let string = ValueConversionOptions {
+            numeric_mode: NumericMode::String,
+            ..options()
+        };
+        assert_eq!(
+            type_schema(Type::Int64, &string),
+            json!({ "type": "string" })
+        );
+        assert_eq!(
+            type_schema(Type::Uint64, &string),
+            json!({ "type": "string" })
+        );
+        assert_eq!(type_schema(Type::Bit, &string), decimal);
+        assert_eq!(
+            type_schema(Type::Int32, &string),
+            json!({ "type": "int32" })
+        );
+
+        let scaled = ValueConversionOptions {
+            numeric_mode: NumericMode::ScaledInteger,
+            ..options()
+        };
+        assert_eq!(
+            type_schema(Type::Int64, &scaled),
+            json!({ "type": "int64" })
+        );
+        assert_eq!(
+            type_schema(Type::Uint64, &scaled),
+            json!({ "type": "string" })
+        );
+    }
+
+    #[test]
+    fn declares_decimal_types() {
+        let with_mode = |numeric_mode| ValueConversionOptions {
+            numeric_mode,
+            ..options()
+        };
+        assert_eq!(
+            type_schema(Type::Decimal, &with_mode(NumericMode::Number)),
+            json!({ "type": "double" })
+        );
+        assert_eq!(
+            type_schema(Type::Decimal, &with_mode(NumericMode::String)),
+            json!({ "type": "string" })
+        );
+        assert_eq!(
+            type_schema(Type::Decimal, &with_mode(NumericMode::ScaledInteger))["type"],
+            json!("struct")
+        );
+        assert_eq!(
+            type_schema(Type::Float32, &options()),
+            json!({ "type": "float" })
+        );
+        assert_eq!(
+            type_schema(Type::Float64, &options()),
+            json!({ "type": "double" })
+        );
+    }
+
+    #[test]
+    fn declares_string_and_binary_types() {
+        assert_eq!(
+            type_schema(Type::Varchar, &options()),
+            json!({ "type": "string" })
+        );
+        assert_eq!(
+            type_schema(Type::Enum, &options()),
+            json!({ "type": "string", "name": "io.debezium.data.Enum" })
+        );
+        assert_eq!(
+            type_schema(Type::Set, &options()),
+            json!({ "type": "array", "items": { "type": "string" } })
+        );
+        assert_eq!(
+            type_schema(Type::Json, &options()),
+            json!({ "type": "string", "name": "io.debezium.data.Json" })
+        );
+        assert_eq!(
+            type_schema(Type::Blob, &options()),
+            json!({ "type": "bytes" })
+        );
+        let hex = ValueConversionOptions {
+            binary_encoding: BinaryEncoding::Hex,
+            ..options()
+        };
+        assert_eq!(
+            type_schema(Type::Varbinary, &hex),
+            json!({ "type": "string" })
+        );
+        assert_eq!(
+            type_schema(Type::Geometry, &hex)["fields"][0],
+            json!({ "type": "string", "optional": false, "field": "wkb" })
+        );
+        assert_eq!(
+            type_schema(Type::Vector, &options()),
+            json!({ "type": "array", "items": { "type": "float" } })
+        );
+    }
+
+    #[test]
+    fn declares_temporal_types() {
+        let with_mode = |temporal_mode| ValueConversionOptions {
+            temporal_mode,
+            ..options()
+        };
+        let iso = with_mode(TemporalMode::Iso8601);
+        assert_eq!(
+            type_schema(Type::Date, &iso),
+            json!({ "type": "string", "name": "io.debezium.time.IsoDate" })
+        );
+        assert_eq!(
+            type_schema(Type::Datetime, &iso),
+            json!({ "type": "string", "name": "io.debezium.time.ZonedTimestamp" })
+        );
+        assert_eq!(
+            type_schema(Type::Time, &iso),
+            json!({ "type": "string", "name": "io.debezium.time.Interval" })
+        );
+
+        let millis = with_mode(TemporalMode::EpochMillis);
+        assert_eq!(
+            type_schema(Type::Date, &millis),
+            json!({ "type": "int64", "name": "io.debezium.time.Timestamp" })
+        );
+        assert_eq!(type_schema(Type::Time, &millis), json!({ "type": "int64" }));
+
+        let micros = with_mode(TemporalMode::EpochMicros);
+        assert_eq!(
+            type_schema(Type::Timestamp, &micros),
+            json!({ "type": "int64", "name": "io.debezium.time.MicroTimestamp" })
+        );
+        assert_eq!(
+            type_schema(Type::Time, &micros),
+            json!({ "type": "int64", "name": "io.debezium.time.MicroDuration" })
+        );
+    }
+
+    #[test]
+    fn writes_the_payload() {
+        let update = envelope(ReplicationRowEvent::Update {
+            before: Row::default(),
+            after: Row::default(),
+        });
+        let value = debezium_envelope(
+            &update,
+            row_map(json!({ "id": 1, "status": "new" })),
+            row_map(json!({ "id": 1, "status": "paid" })),
+            &schema(),
+            &options(),
+            false,
+        );
+
+        assert_eq!(value["before"], json!({ "id": 1, "status": "new" }));
+        assert_eq!(value["after"], json!({ "id": 1, "status": "paid" }));
+        assert_eq!(value["op"], json!("u"));
+        assert!(value["ts_ms"].is_u64());
+        assert_eq!(
+            value["source"],
+            json!({
+                "connector": "vitess",
+                "keyspace": "commerce",
+                "shard": "-80",
+                "table": "orders",
+                "gtid": "uuid:42",
+                "ts_ms": 1_700_000_000_000i64,
+                "snapshot": "false",
+            })
+        );
+
+        let delete = envelope(ReplicationRowEvent::Delete(Row::default()));
+        let value = debezium_envelope(
+            &delete,
+            row_map(json!({ "id": 1, "status": "new" })),
+            None,
+            &schema(),
+            &options(),
+            false,
+        );
+        assert_eq!(value["after"], serde_json::Value::Null);
+        assert_eq!(value["op"], json!("d"));
+    }
+
+    #[test]
+    fn wraps_the_payload_with_its_schema() {
+        let insert = envelope(ReplicationRowEvent::SnapshotRead(Row::default()));
+        let value = debezium_envelope(
+            &insert,
+            None,
+            row_map(json!({ "id": 1, "status": "new" })),
+            &schema(),
+            &options(),
+            true,
+        );
+
+        assert_eq!(value["payload"]["op"], json!("r"));
+        assert_eq!(value["payload"]["source"]["snapshot"], json!("true"));
+        let envelope_schema = &value["schema"];
+        assert_eq!(envelope_schema["name"], json!("commerce.orders.Envelope"));
+        let fields: Vec<&serde_json::Value> = envelope_schema["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|field| &field["field"])
+            .collect();
+        assert_eq!(fields, ["before", "after", "source", "op", "ts_ms"]);
+        assert_eq!(
+            envelope_schema["fields"][1]["fields"],
+            json!([
+                { "type": "int64", "optional": false, "field": "id" },
+                { "type": "string", "optional": true, "field": "status" },
+            ])
+        );
+        assert_eq!(
+            envelope_schema["fields"][1]["name"],
+            json!("commerce.orders.Value")
+        );
+
+        let key = debezium_key(&insert, json!({ "id": 1 }), &schema(), &options(), true);
+        assert_eq!(
+            key,
+            json!({
+                "schema": {
+                    "type": "struct",
+                    "name": "commerce.orders.Key",
+                    "optional": false,
+                    "fields": [{ "type": "int64", "optional": false, "field": "id" }],
+                },
+                "payload": { "id": 1 },
+            })
+        );
+        assert_eq!(
+            debezium_key(&insert, json!({ "id": 1 }), &schema(), &options(), false),
+            json!({ "id": 1 })
+        );
+    }
+}
@@ -0,0 +1,450 @@
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    sync::{
+        Mutex,
+        mpsc::{Receiver, RecvTimeoutError},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use rdkafka::{
+    ClientConfig, ClientContext,
+    error::{KafkaError, RDKafkaErrorCode},
+    message::{Header, OwnedHeaders},
+    producer::{BaseRecord, DeliveryResult, Producer, ProducerContext, ThreadedProducer},
+};
+
+use crate::{
+    command_line_args::Args,
+    table_row_change_json_converter::{
+        JsonDeadLetterRecord, JsonRowRecord, JsonSchemaChangeRecord, JsonStreamMessage,
+        JsonTransactionRecord,
+    },
+    vgtid_checkpoint_store::{CheckpointStoreError, VGtidCheckpointStore},
+    vitess_grpc::binlogdata::VGtid,
+};
+
+const QUEUE_FULL_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct KafkaStreamProducerError {
+    pub kind: KafkaStreamProducerErrorKind,
+}
+
+impl Display for KafkaStreamProducerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "error streaming outgoing events to kafka",)
+    }
+}
+
+impl Error for KafkaStreamProducerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self.kind {
+            KafkaStreamProducerErrorKind::CreateProducerFailed(e) => Some(e),
+            KafkaStreamProducerErrorKind::ProduceFailed(e) => Some(e),
+            KafkaStreamProducerErrorKind::DeliveryFailed(e) => Some(e),
+            KafkaStreamProducerErrorKind::CheckpointFailed(e) => Some(e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum KafkaStreamProducerErrorKind {
+    CreateProducerFailed(KafkaError),
+    ProduceFailed(KafkaError),
+    DeliveryFailed(KafkaError),
+    CheckpointFailed(CheckpointStoreError),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct KafkaProducerConfig {
+    pub(crate) brokers: String,
+    pub(crate) topic_template: String,
+    pub(crate) transaction_topic_template: String,
+    pub(crate) schema_change_topic_template: String,
+    pub(crate) dead_letter_topic_template: String,
+    pub(crate) checkpoint_interval: Duration,
+    pub(crate) flush_timeout: Duration,
+    pub(crate) properties: Vec<(String, String)>,
+}
+
+impl From<&Args> for KafkaProducerConfig {
+    fn from(args: &Args) -> Self {
+        KafkaProducerConfig {
+            brokers: args.kafka_brokers.clone(),
+            topic_template: args.kafka_topic_template.clone(),
+            transaction_topic_template: args.kafka_transaction_topic_template.clone(),
+            schema_change_topic_template: args.kafka_schema_change_topic_template.clone(),
+            dead_letter_topic_template: args.kafka_dead_letter_topic_template.clone(),
+            checkpoint_interval: Duration::from_millis(args.kafka_checkpoint_interval_ms),
+            flush_timeout: Duration::from_millis(args.kafka_flush_timeout_ms),
+            properties: args.kafka_property.clone(),
+        }
+    }
+}
+
+// Holds back the latest checkpoint until every record produced before it was delivered. The first
+// failed delivery report is kept for good, nothing after it may be checkpointed.
+#[derive(Default)]
+struct DeliveryTracker {
+    pending_checkpoint: Mutex<Option<VGtid>>,
+    failed_delivery: Mutex<Option<KafkaError>>,
+}
+
+impl DeliveryTracker {
+    fn on_checkpoint(&self, vgtid: VGtid) {
+        *self
+            .pending_checkpoint
+            .lock()
+            .expect("Pending checkpoint lock should not be poisoned") = Some(vgtid);
+    }
+
+    fn on_delivery_failed(&self, e: &KafkaError) {
+        self.failed_delivery
+            .lock()
+            .expect("Delivery report lock should not be poisoned")
+            .get_or_insert_with(|| e.clone());
+    }
+
+    fn has_pending_checkpoint(&self) -> bool {
+        self.pending_checkpoint
+            .lock()
+            .expect("Pending checkpoint lock should not be poisoned")
+            .is_some()
+    }
+
+    /// Fails if any record was not delivered, call after flushing the producer
+    fn delivered(&self) -> Result<(), KafkaStreamProducerError> {
+        match &*self
+            .failed_delivery
+            .lock()
+            .expect("Delivery report lock should not be poisoned")
+        {
+            Some(e) => Err(KafkaStreamProducerError {
+                kind: KafkaStreamProducerErrorKind::DeliveryFailed(e.clone()),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Takes the pending checkpoint once everything before it was delivered, call after flushing
+    /// the producer
+    fn take_delivered_checkpoint(&self) -> Result<Option<VGtid>, KafkaStreamProducerError> {
+        self.delivered()?;
+        Ok(self
+            .pending_checkpoint
+            .lock()
+            .expect("Pending checkpoint lock should not be poisoned")
+            .take())
+    }
+}
+
+impl ClientContext for DeliveryTracker {}
+
+impl ProducerContext for DeliveryTracker {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, delivery_result: &DeliveryResult<'_>, _delivery_opaque: ()) {
+        if let Err((e, _)) = delivery_result {
+            log::error!("Failed to deliver record to kafka: {}", e);
+            self.on_delivery_failed(e);
+        }
+    }
+}
+
+pub(crate) fn run_kafka_stream_producer(
+    incoming_json: Receiver<JsonStreamMessage>,
+    mut checkpoint_store: Box<dyn VGtidCheckpointStore>,
+    config: KafkaProducerConfig,
+) -> Result<(), KafkaStreamProducerError> {
+    let producer = create_producer(&config)?;
+
+    let mut last_checkpoint = Instant::now();
+    loop {
+        let message = match incoming_json.recv_timeout(config.checkpoint_interval) {
+            Ok(message) => message,
+            Err(RecvTimeoutError::Timeout) => {
+                commit_checkpoint(&producer, checkpoint_store.as_mut(), &config)?;
+                last_checkpoint = Instant::now();
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                // The converter hangs up once the pipeline is drained, deliver what is left and
+                // record how far we got so a restart neither loses nor repeats transactions
+                commit_checkpoint(&producer, checkpoint_store.as_mut(), &config)?;
+                producer
+                    .flush(config.flush_timeout)
+                    .map_err(|e| KafkaStreamProducerError {
+                        kind: KafkaStreamProducerErrorKind::DeliveryFailed(e),
+                    })?;
+                // Nothing is checkpointed after a failed delivery, the records produced since the
+                // last checkpoint may still have failed
+                producer.context().delivered()?;
+                log::info!("Kafka sink drained");
+                return Ok(());
+            }
+        };
+
+        match message {
+            JsonStreamMessage::Row(record) => produce_record(&producer, &config, record)?,
+            JsonStreamMessage::DeadLetter(record) => {
+                produce_dead_letter_record(&producer, &config, record)?
+            }
+            JsonStreamMessage::SchemaChange(record) => {
+                produce_schema_change_record(&producer, &config, record)?
+            }
+            JsonStreamMessage::TransactionCommitted(record) => {
+                produce_transaction_record(&producer, &config, record)?
+            }
+            JsonStreamMessage::Checkpoint(vgtid) => {
+                producer.context().on_checkpoint(vgtid);
+                if last_checkpoint.elapsed() >= config.checkpoint_interval {
+                    commit_checkpoint(&producer, checkpoint_store.as_mut(), &config)?;
+                    last_checkpoint = Instant::now();
+                }
+            }
+        }
+    }
+}
+
+fn create_producer(
+    config: &KafkaProducerConfig,
+) -> Result<ThreadedProducer<DeliveryTracker>, KafkaStreamProducerError> {
+    let mut client_config = ClientConfig::new();
+    client_config
+        .set("bootstrap.servers", &config.brokers)
+        .set("enable.idempotence", "true")
+        .set("acks", "all");
+    for (key, value) in config.properties.iter() {
+        client_config.set(key, value);
+    }
+
+    client_config
+        .create_with_context(DeliveryTracker::default())
+        .map_err(|e| KafkaStreamProducerError {
+            kind: KafkaStreamProducerErrorKind::CreateProducerFailed(e),
+        })
+}
+
+fn produce_record(
+    producer: &ThreadedProducer<DeliveryTracker>,
+    config: &KafkaProducerConfig,
+    record: JsonRowRecord,
+) -> Result<(), KafkaStreamProducerError> {
+    let topic = config
+        .topic_template
+        .replace("{keyspace}", &record.keyspace.to_string())
+        .replace("{table}", &record.table.to_string());
+    let key = record.key.map(|key| key.to_string());
+    let payload = record.value.map(|value| value.to_string());
+    // Lets consumers group the records of a transaction without parsing the payload
+    let headers = OwnedHeaders::new()
+        .insert(Header {
+            key: "transaction_id",
+            value: Some(&record.transaction.id),
+        })
+        .insert(Header {
+            key: "shard",
+            value: Some(&record.transaction.shard),
+        })
+        .insert(Header {
+            key: "sequence",
+            value: Some(&record.transaction.sequence.to_string()),
+        })
+        .insert(Header {
+            key: "timestamp",
+            value: Some(&record.transaction.timestamp.to_string()),
+        });
+
+    send_with_retry(
+        producer,
+        &topic,
+        key.as_deref(),
+        payload.as_deref(),
+        Some(headers),
+    )
+}
+
+fn produce_dead_letter_record(
+    producer: &ThreadedProducer<DeliveryTracker>,
+    config: &KafkaProducerConfig,
+    record: JsonDeadLetterRecord,
+) -> Result<(), KafkaStreamProducerError> {
+    let table = record.table.to_string();
+    let topic = config
+        .dead_letter_topic_template
+        .replace("{keyspace}", &record.keyspace.to_string())
+        .replace("{table}", &table);
+
+    // Keyed by table so the rows of a table are replayed in the order they failed
+    send_with_retry(
+        producer,
+        &topic,
+        Some(&table),
+        Some(&record.value.to_string()),
+        None,
+    )
+}
+
+fn produce_schema_change_record(
+    producer: &ThreadedProducer<DeliveryTracker>,
+    config: &KafkaProducerConfig,
+    record: JsonSchemaChangeRecord,
+) -> Result<(), KafkaStreamProducerError> {
+    let keyspace = record.keyspace.to_string();
+    let topic = config
+        .schema_change_topic_template
+        .replace("{keyspace}", &keyspace);
+
+    // Keyed by keyspace so all schema changes of a keyspace stay in order on one partition
+    send_with_retry(
+        producer,
+        &topic,
+        Some(&keyspace),
+        Some(&record.value.to_string()),
+        None,
+    )
+}
+
+fn produce_transaction_record(
+    producer: &ThreadedProducer<DeliveryTracker>,
+    config: &KafkaProducerConfig,
+    record: JsonTransactionRecord,
+) -> Result<(), KafkaStreamProducerError> {
+    let topic = config
+        .transaction_topic_template
+        .replace("{keyspace}", &record.keyspace.to_string());
+
+    send_with_retry(
+        producer,
+        &topic,
+        Some(&record.id),
+        Some(&record.value.to_string()),
+        None,
+    )
+}
+
+fn send_with_retry(
+    producer: &ThreadedProducer<DeliveryTracker>,
+    topic: &str,
+    key: Option<&str>,
+    payload: Option<&str>,
+    headers: Option<OwnedHeaders>,
+) -> Result<(), KafkaStreamProducerError> {
+    loop {
+        let mut kafka_record = BaseRecord::<str, str>::to(topic);
+        // A record without a payload is a tombstone
+        if let Some(payload) = payload {
+            kafka_record = kafka_record.payload(payload);
+        }
+        if let Some(key) = key {
+            kafka_record = kafka_record.key(key);
+        }
+        if let Some(headers) = &headers {
+            kafka_record = kafka_record.headers(headers.clone());
+        }
+
+        match producer.send(kafka_record) {
+            Ok(()) => return Ok(()),
+            Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), _)) => {
+                // The background thread polls for delivery reports, wait for it to free up space
+                log::debug!("Kafka producer queue is full, waiting before retrying...");
+                thread::sleep(QUEUE_FULL_BACKOFF);
+            }
+            Err((e, _)) => {
+                return Err(KafkaStreamProducerError {
+                    kind: KafkaStreamProducerErrorKind::ProduceFailed(e),
+                });
+            }
+        }
+    }
+}
+
+fn commit_checkpoint(
+    producer: &ThreadedProducer<DeliveryTracker>,
+    checkpoint_store: &mut dyn VGtidCheckpointStore,
+    config: &KafkaProducerConfig,
+) -> Result<(), KafkaStreamProducerError> {
+    if !producer.context().has_pending_checkpoint() {
+        return Ok(());
+    }
+
+    // Wait for the delivery reports of every record produced before the checkpoint
+    producer
+        .flush(config.flush_timeout)
+        .map_err(|e| KafkaStreamProducerError {
+            kind: KafkaStreamProducerErrorKind::DeliveryFailed(e),
+        })?;
+    let Some(vgtid) = producer.context().take_delivered_checkpoint()? else {
+        return Ok(());
+    };
+
+    checkpoint_store
+        .save(&vgtid)
+        .map_err(|e| KafkaStreamProducerError {
+            kind: KafkaStreamProducerErrorKind::CheckpointFailed(e),
+        })?;
+    log::debug!("Saved checkpoint {:?}", vgtid);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timed_out() -> KafkaError {
+        KafkaError::MessageProduction(RDKafkaErrorCode::MessageTimedOut)
+    }
+
+    #[test]
+    fn takes_a_delivered_checkpoint_once() {
+        let tracker = DeliveryTracker::default();
+        tracker.on_checkpoint(VGtid::default());
+
+        assert!(tracker.has_pending_checkpoint());
+        assert_eq!(
+            tracker.take_delivered_checkpoint().unwrap(),
+            Some(VGtid::default())
+        );
+        assert!(!tracker.has_pending_checkpoint());
+        assert_eq!(tracker.take_delivered_checkpoint().unwrap(), None);
+    }
+
+    #[test]
+    fn commits_no_checkpoint_after_a_failed_delivery() {
+        let tracker = DeliveryTracker::default();
+        tracker.on_checkpoint(VGtid::default());
+        tracker.on_delivery_failed(&timed_out());
+
+        assert!(matches!(
+            tracker.take_delivered_checkpoint(),
+            Err(KafkaStreamProducerError {
+                kind: KafkaStreamProducerErrorKind::DeliveryFailed(_)
+            })
+        ));
+
+        // Records delivered later don't make up for the lost one
+        tracker.on_checkpoint(VGtid::default());
+        assert!(tracker.take_delivered_checkpoint().is_err());
+    }
+
+    #[test]
+    fn surfaces_a_failed_delivery_without_a_pending_checkpoint() {
+        let tracker = DeliveryTracker::default();
+        assert!(tracker.delivered().is_ok());
+
+        // The converter hung up with no checkpoint after the failed record
+        tracker.on_delivery_failed(&timed_out());
+        assert!(!tracker.has_pending_checkpoint());
+        assert!(matches!(
+            tracker.delivered(),
+            Err(KafkaStreamProducerError {
+                kind: KafkaStreamProducerErrorKind::DeliveryFailed(_)
+            })
+        ));
+    }
+}
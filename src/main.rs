@@ -1,83 +1,211 @@
 mod command_line_args;
 mod console_stream_producer;
+mod debezium_envelope;
+mod kafka_stream_producer;
+mod mysql_binary_json;
+mod pipeline_supervisor;
 mod replication_row_event;
+mod shutdown_signal;
 mod table_row_change_json_converter;
 mod table_row_deserializer;
+mod table_row_temporal;
+mod vgtid_checkpoint_store;
 mod vitess_clients;
 mod vitess_grpc;
 mod vitess_schema;
+mod vitess_schema_registry;
 mod vitess_shards;
 mod vitess_snapshot;
+mod vitess_table_filter;
+mod vitess_vstream_copy;
 mod vitess_vstream_listener;
+mod vitess_vstream_reconnect;
+mod vitess_vstream_transaction;
 
+use std::process::ExitCode;
 use std::sync::mpsc;
 
-use tokio::select;
+use tokio::sync::{oneshot, watch};
 
+use crate::command_line_args::{Args, InitialLoadMode, JsonColumnMode, SinkType};
 use crate::console_stream_producer::run_console_stream_producer;
-use crate::table_row_change_json_converter::start_row_change_json_converter;
+use crate::kafka_stream_producer::{KafkaProducerConfig, run_kafka_stream_producer};
+use crate::pipeline_supervisor::{PipelineStage, PipelineSupervisor};
+use crate::shutdown_signal::ShutdownSignal;
+use crate::table_row_change_json_converter::{
+    RowEnvelopeOptions, RowErrorPolicies, start_row_change_json_converter,
+};
+use crate::table_row_deserializer::ValueConversionOptions;
+use crate::vgtid_checkpoint_store::create_checkpoint_store;
 use crate::vitess_clients::{create_vtctld_client, create_vtgate_client};
-use crate::vitess_schema::{TableName, get_schema_for_tables};
+use crate::vitess_schema::get_schema_for_tables;
+use crate::vitess_schema_registry::SchemaRegistry;
 use crate::vitess_shards::KeyspaceName;
-use crate::vitess_vstream_listener::start_vitess_vstream_listener;
-use clap::Parser;
+use crate::vitess_snapshot::{SnapshotConfig, SnapshotWatermarkTracker, start_vitess_snapshot};
+use crate::vitess_table_filter::{build_table_filter, find_vstream_only_filter, project_schemas};
+use crate::vitess_vstream_listener::{
+    VStreamOptions, VStreamSourceConfig, get_start_vgtid, start_vitess_vstream_listener,
+};
+use crate::vitess_vstream_reconnect::ReconnectConfig;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
 
 use env_logger;
 use log;
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> Result<ExitCode, Box<dyn std::error::Error>> {
     env_logger::init();
 
     log::info!("Starting vitess replicator...");
 
-    let args = command_line_args::Args::parse();
+    let args = Args::parse();
+    // The schema declares `JSON` columns as strings, nested documents wouldn't match it
+    if args.debezium_schema && args.json_column_mode != JsonColumnMode::String {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "`--debezium-schema` requires `--json-column-mode string`",
+            )
+            .exit();
+    }
+    if args.initial_load == InitialLoadMode::Snapshot
+        && let Some(table) = find_vstream_only_filter(&args.tables)
+    {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                format!(
+                    "`--initial-load snapshot` can't run the `in_keyrange` filter of `{}`",
+                    table
+                ),
+            )
+            .exit();
+    }
+    let mut shutdown_signal = ShutdownSignal::install()?;
+    let (shutdown_sender, shutdown) = watch::channel(false);
+    let mut supervisor = PipelineSupervisor::new(shutdown_sender);
+
     log::info!("Connecting to vtctld...");
     let mut vtctld_client = create_vtctld_client(&args).await?;
 
     log::info!("Connecting to vtgate...");
     let vtgate_client = create_vtgate_client(&args).await?;
 
+    let checkpoint_store = create_checkpoint_store(&args);
+    let checkpoint = checkpoint_store.load()?;
+    let initial_load = args.initial_load;
+    let snapshot_config = SnapshotConfig::from(&args);
+    let sink = args.sink;
+    let kafka_config = KafkaProducerConfig::from(&args);
+    let reconnect_config = ReconnectConfig::from(&args);
+    let source_config = VStreamSourceConfig::from(&args);
+    let transaction_markers = args.transaction_markers;
+    let error_policies = RowErrorPolicies::from(&args);
+    let conversion_options = ValueConversionOptions::from(&args);
+    let envelope_options = RowEnvelopeOptions::from(&args);
+    let channel_capacity = args.channel_capacity as usize;
+
     let keyspace: KeyspaceName = args.keyspace.into();
 
-    let (outgoing_row_changes, incoming_row_changes) = mpsc::channel();
-    let (outgoing_json, incoming_json) = mpsc::channel();
+    // Bounded so a slow sink blocks the converter, which in turn stops the listener from reading
+    // the vstream. The converter and sink run on blocking threads, hence the std channel between them
+    let (outgoing_row_changes, incoming_row_changes) = tokio::sync::mpsc::channel(channel_capacity);
+    let (outgoing_json, incoming_json) = mpsc::sync_channel(channel_capacity);
 
-    let tables_to_replicate: Vec<TableName> =
-        args.tables.into_iter().map(TableName::from).collect();
+    let table_rules = args.tables;
 
     log::info!(
         "Tables to replicate: {:?}; fetching schemas...",
-        tables_to_replicate
+        table_rules
     );
-    let schemas = get_schema_for_tables(
-        &mut vtctld_client,
-        &keyspace,
-        tables_to_replicate.as_slice(),
-    )
-    .await?;
+    let schemas =
+        get_schema_for_tables(&mut vtctld_client, &keyspace, table_rules.as_slice()).await?;
+    let schemas = project_schemas(&table_rules, schemas)?;
     log::info!("Schemas = {:?}", schemas);
 
+    let run_snapshot = initial_load == InitialLoadMode::Snapshot && checkpoint.is_none();
+    // The signal table has to be streamed for the snapshot to see its watermarks
+    let extra_tables = if run_snapshot {
+        vec![snapshot_config.signal_table.clone()]
+    } else {
+        vec![]
+    };
+    let filter = build_table_filter(&table_rules, &schemas, &extra_tables);
+    let schema_registry = SchemaRegistry::new(schemas.clone());
+
+    let snapshot_tracker = if run_snapshot {
+        log::info!("No checkpoint found, starting snapshot of all tables...");
+        // Unbounded on purpose: the snapshot waits for the listener to emit each chunk before it
+        // reads the next one, so this channel and the tracker hold at most one chunk of
+        // `--snapshot-chunk-size` rows besides its start and completion signals
+        let (outgoing_signals, incoming_signals) = mpsc::channel();
+        let (stream_started_sender, stream_started) = oneshot::channel();
+        supervisor.spawn(
+            PipelineStage::Snapshot,
+            start_vitess_snapshot(
+                vtctld_client.clone(),
+                vtgate_client.clone(),
+                keyspace.clone(),
+                schema_registry.clone(),
+                snapshot_config.clone(),
+                stream_started,
+                outgoing_signals,
+            ),
+        );
+        SnapshotWatermarkTracker::new(
+            snapshot_config.signal_table,
+            incoming_signals,
+            stream_started_sender,
+        )
+    } else {
+        SnapshotWatermarkTracker::completed()
+    };
+
+    let vgtid = get_start_vgtid(&mut vtctld_client, &keyspace, checkpoint, initial_load).await?;
+
     log::info!("Starting vstream listener...");
-    let vstream_listener_handle = tokio::task::spawn(start_vitess_vstream_listener(
-        vtctld_client,
-        vtgate_client,
-        keyspace,
-        outgoing_row_changes,
-    ));
-
-    let row_change_converter_thread = tokio::task::spawn_blocking(move || {
-        start_row_change_json_converter(incoming_row_changes, outgoing_json, schemas.clone())
-    });
+    supervisor.spawn(
+        PipelineStage::Listener,
+        start_vitess_vstream_listener(
+            vtgate_client,
+            keyspace,
+            vgtid,
+            VStreamOptions {
+                filter,
+                source: source_config,
+                reconnect: reconnect_config,
+                transaction_markers,
+                shutdown,
+            },
+            schema_registry.clone(),
+            snapshot_tracker,
+            outgoing_row_changes,
+        ),
+    );
 
-    let console_producer_thread =
-        tokio::task::spawn_blocking(move || run_console_stream_producer(incoming_json));
+    // Stopping the listener closes the channels behind it, so the converter and the sink finish
+    // whatever is in flight and the sink writes its final checkpoint before they return
+    supervisor.spawn_blocking(PipelineStage::Converter, move || {
+        start_row_change_json_converter(
+            incoming_row_changes,
+            outgoing_json,
+            schema_registry,
+            error_policies,
+            conversion_options,
+            envelope_options,
+        )
+    });
 
-    select! {
-        _ = vstream_listener_handle => (),
-        _ = row_change_converter_thread => (),
-        _ = console_producer_thread => (),
+    log::info!("Starting {:?} sink...", sink);
+    match sink {
+        SinkType::Console => supervisor.spawn_blocking(PipelineStage::Sink, move || {
+            run_console_stream_producer(incoming_json, checkpoint_store)
+        }),
+        SinkType::Kafka => supervisor.spawn_blocking(PipelineStage::Sink, move || {
+            run_kafka_stream_producer(incoming_json, checkpoint_store, kafka_config)
+        }),
     }
 
-    Ok(())
+    Ok(supervisor.run(&mut shutdown_signal).await)
 }
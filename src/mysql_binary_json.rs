@@ -0,0 +1,562 @@
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    str::{FromStr, Utf8Error},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use serde_json::{Map, Number};
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct BinaryJsonError {
+    pub kind: BinaryJsonErrorKind,
+}
+
+impl Display for BinaryJsonError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            BinaryJsonErrorKind::Truncated => write!(f, "binary json value is truncated"),
+            BinaryJsonErrorKind::UnknownValueType(value_type) => {
+                write!(f, "unknown binary json value type `{:#04x}`", value_type)
+            }
+            BinaryJsonErrorKind::InvalidString(_) => {
+                write!(f, "binary json string is not valid utf8")
+            }
+        }
+    }
+}
+
+impl Error for BinaryJsonError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self.kind {
+            BinaryJsonErrorKind::InvalidString(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BinaryJsonErrorKind {
+    Truncated,
+    UnknownValueType(u8),
+    InvalidString(Utf8Error),
+}
+
+const SMALL_OBJECT: u8 = 0x00;
+const LARGE_OBJECT: u8 = 0x01;
+const SMALL_ARRAY: u8 = 0x02;
+const LARGE_ARRAY: u8 = 0x03;
+const LITERAL: u8 = 0x04;
+const INT16: u8 = 0x05;
+const UINT16: u8 = 0x06;
+const INT32: u8 = 0x07;
+const UINT32: u8 = 0x08;
+const INT64: u8 = 0x09;
+const UINT64: u8 = 0x0a;
+const DOUBLE: u8 = 0x0b;
+const STRING: u8 = 0x0c;
+const OPAQUE: u8 = 0x0f;
+
+const LITERAL_NULL: u8 = 0x00;
+const LITERAL_TRUE: u8 = 0x01;
+const LITERAL_FALSE: u8 = 0x02;
+
+// Mysql column types that opaque values carry
+const MYSQL_TYPE_TIMESTAMP: u8 = 7;
+const MYSQL_TYPE_DATE: u8 = 10;
+const MYSQL_TYPE_TIME: u8 = 11;
+const MYSQL_TYPE_DATETIME: u8 = 12;
+const MYSQL_TYPE_NEWDECIMAL: u8 = 246;
+
+/// Whether the first byte of a value could start a binary json document, json text never starts
+/// with these bytes except for tabs and newlines
+pub(crate) fn is_binary_json_type(byte: u8) -> bool {
+    matches!(byte, SMALL_OBJECT..=STRING | OPAQUE)
+}
+
+/// Decodes the binary format mysql stores json columns in and writes to the binlog. An empty
+/// value is the json `null` literal.
+pub(crate) fn decode_binary_json(value: &[u8]) -> Result<serde_json::Value, BinaryJsonError> {
+    let Some((&value_type, data)) = value.split_first() else {
+        return Ok(serde_json::Value::Null);
+    };
+    decode_value(value_type, data)
+}
+
+fn truncated() -> BinaryJsonError {
+    BinaryJsonError {
+        kind: BinaryJsonErrorKind::Truncated,
+    }
+}
+
+fn bytes<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N], BinaryJsonError> {
+    data.get(offset..offset + N)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(truncated)
+}
+
+fn read_uint(data: &[u8], offset: usize, large: bool) -> Result<usize, BinaryJsonError> {
+    Ok(if large {
+        u32::from_le_bytes(bytes(data, offset)?) as usize
+    } else {
+        usize::from(u16::from_le_bytes(bytes(data, offset)?))
+    })
+}
+
+// Lengths of strings and opaque values use 7 bits per byte, least significant group first
+fn read_variable_length(data: &[u8]) -> Result<(usize, usize), BinaryJsonError> {
+    let mut length = 0usize;
+    for (i, byte) in data.iter().take(5).enumerate() {
+        length |= usize::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((length, i + 1));
+        }
+    }
+    Err(truncated())
+}
+
+fn decode_value(value_type: u8, data: &[u8]) -> Result<serde_json::Value, BinaryJsonError> {
+    match value_type {
+        SMALL_OBJECT => decode_container(data, false, true),
+        LARGE_OBJECT => decode_container(data, true, true),
+        SMALL_ARRAY => decode_container(data, false, false),
+        LARGE_ARRAY => decode_container(data, true, false),
+        LITERAL => match data.first() {
+            Some(&LITERAL_NULL) => Ok(serde_json::Value::Null),
+            Some(&LITERAL_TRUE) => Ok(serde_json::Value::Bool(true)),
+            Some(&LITERAL_FALSE) => Ok(serde_json::Value::Bool(false)),
+            Some(&literal) => Err(BinaryJsonError {
+                kind: BinaryJsonErrorKind::UnknownValueType(literal),
+            }),
+            None => Err(truncated()),
+        },
+        INT16 => Ok(i16::from_le_bytes(bytes(data, 0)?).into()),
+        UINT16 => Ok(u16::from_le_bytes(bytes(data, 0)?).into()),
+        INT32 => Ok(i32::from_le_bytes(bytes(data, 0)?).into()),
+        UINT32 => Ok(u32::from_le_bytes(bytes(data, 0)?).into()),
+        INT64 => Ok(i64::from_le_bytes(bytes(data, 0)?).into()),
+        UINT64 => Ok(u64::from_le_bytes(bytes(data, 0)?).into()),
+        DOUBLE => Ok(Number::from_f64(f64::from_le_bytes(bytes(data, 0)?))
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null)),
+        STRING => {
+            let (length, header) = read_variable_length(data)?;
+            let string = data.get(header..header + length).ok_or_else(truncated)?;
+            Ok(serde_json::Value::String(decode_string(string)?))
+        }
+        OPAQUE => {
+            let (&mysql_type, data) = data.split_first().ok_or_else(truncated)?;
+            let (length, header) = read_variable_length(data)?;
+            let opaque = data.get(header..header + length).ok_or_else(truncated)?;
+            Ok(decode_opaque(mysql_type, opaque))
+        }
+        value_type => Err(BinaryJsonError {
+            kind: BinaryJsonErrorKind::UnknownValueType(value_type),
+        }),
+    }
+}
+
+fn decode_string(string: &[u8]) -> Result<String, BinaryJsonError> {
+    std::str::from_utf8(string)
+        .map(str::to_string)
+        .map_err(|e| BinaryJsonError {
+            kind: BinaryJsonErrorKind::InvalidString(e),
+        })
+}
+
+// Element count and byte size, then a (offset, length) entry per key for objects, then a
+// (type, offset or inlined value) entry per value. Offsets are relative to the container.
+fn decode_container(
+    data: &[u8],
+    large: bool,
+    object: bool,
+) -> Result<serde_json::Value, BinaryJsonError> {
+    let offset_size = if large { 4 } else { 2 };
+    let element_count = read_uint(data, 0, large)?;
+    let size = read_uint(data, offset_size, large)?;
+    let data = data.get(..size).ok_or_else(truncated)?;
+
+    let key_entry_size = offset_size + 2;
+    let value_entry_size = 1 + offset_size;
+    let header_size = 2 * offset_size;
+    let value_entries = header_size
+        + if object {
+            element_count * key_entry_size
+        } else {
+            0
+        };
+
+    let mut values = Vec::with_capacity(element_count.min(data.len()));
+    for i in 0..element_count {
+        let entry = value_entries + i * value_entry_size;
+        let value_type = *data.get(entry).ok_or_else(truncated)?;
+        // Small scalars are stored in the entry itself instead of behind an offset
+        let inlined = matches!(value_type, LITERAL | INT16 | UINT16)
+            || (large && matches!(value_type, INT32 | UINT32));
+        let value = if inlined {
+            decode_value(value_type, data.get(entry + 1..).ok_or_else(truncated)?)?
+        } else {
+            let offset = read_uint(data, entry + 1, large)?;
+            decode_value(value_type, data.get(offset..).ok_or_else(truncated)?)?
+        };
+        values.push(value);
+    }
+
+    if !object {
+        return Ok(serde_json::Value::Array(values));
+    }
+
+    let mut map = Map::new();
+    for (i, value) in values.into_iter().enumerate() {
+        let entry = header_size + i * key_entry_size;
+        let offset = read_uint(data, entry, large)?;
+        let length = usize::from(u16::from_le_bytes(bytes(data, entry + offset_size)?));
+        let key = data.get(offset..offset + length).ok_or_else(truncated)?;
+        map.insert(decode_string(key)?, value);
+    }
+    Ok(serde_json::Value::Object(map))
+}
+
+// Mysql prints opaque values it knows as strings and everything else as base64
+fn decode_opaque(mysql_type: u8, data: &[u8]) -> serde_json::Value {
+    let decoded = match mysql_type {
+        MYSQL_TYPE_NEWDECIMAL => decode_decimal(data).and_then(|decimal| {
+            Number::from_str(&decimal)
+                .ok()
+                .map(serde_json::Value::Number)
+        }),
+        MYSQL_TYPE_DATE | MYSQL_TYPE_DATETIME | MYSQL_TYPE_TIMESTAMP | MYSQL_TYPE_TIME => data
+            .first_chunk::<8>()
+            .map(|packed| decode_packed_temporal(mysql_type, i64::from_le_bytes(*packed)))
+            .map(serde_json::Value::String),
+        _ => None,
+    };
+    decoded.unwrap_or_else(|| {
+        serde_json::Value::String(format!("base64:type{}:{}", mysql_type, BASE64.encode(data)))
+    })
+}
+
+// Bytes needed for 0 to 8 leftover decimal digits, full groups of 9 digits take 4 bytes
+const DIGITS_TO_BYTES: [usize; 10] = [0, 1, 1, 2, 2, 3, 3, 4, 4, 4];
+
+// Precision and scale followed by the big endian binary decimal format of mysql, which flips
+// the sign bit and stores negative numbers with every bit inverted
+fn decode_decimal(data: &[u8]) -> Option<String> {
+    let (&precision, data) = data.split_first()?;
+    let (&scale, data) = data.split_first()?;
+    let integer_digits = usize::from(precision.checked_sub(scale)?);
+    let scale = usize::from(scale);
+
+    let leading_digits = integer_digits % 9;
+    let trailing_digits = scale % 9;
+    let size = integer_digits / 9 * 4
+        + DIGITS_TO_BYTES[leading_digits]
+        + scale / 9 * 4
+        + DIGITS_TO_BYTES[trailing_digits];
+    let mut bytes = data.get(..size)?.to_vec();
+    let negative = bytes.first().is_some_and(|byte| byte & 0x80 == 0);
+    if let Some(first) = bytes.first_mut() {
+        *first ^= 0x80;
+    }
+    if negative {
+        bytes.iter_mut().for_each(|byte| *byte = !*byte);
+    }
+
+    let mut position = 0;
+    let mut read_group = |digits: usize| {
+        let length = if digits == 9 {
+            4
+        } else {
+            DIGITS_TO_BYTES[digits]
+        };
+        let group = bytes[position..position + length]
+            .iter()
+            .fold(0u64, |group, byte| (group << 8) | u64::from(*byte));
+        position += length;
+        format!("{:0width$}", group, width = digits)
+    };
+
+    let mut integer = read_group(leading_digits);
+    for _ in 0..integer_digits / 9 {
+        integer.push_str(&read_group(9));
+    }
+    let mut fraction = String::new();
+    for _ in 0..scale / 9 {
+        fraction.push_str(&read_group(9));
+    }
+    fraction.push_str(&read_group(trailing_digits));
+
+    let integer = match integer.trim_start_matches('0') {
+        "" => "0",
+        integer => integer,
+    };
+    let sign = if negative { "-" } else { "" };
+    Some(if scale == 0 {
+        format!("{}{}", sign, integer)
+    } else {
+        format!("{}{}.{}", sign, integer, fraction)
+    })
+}
+
+// Packed the way mysql keeps temporal values in memory, formatted like mysql prints them in json
+fn decode_packed_temporal(mysql_type: u8, packed: i64) -> String {
+    let sign = if packed < 0 { "-" } else { "" };
+    let packed = packed.unsigned_abs();
+    let micros = packed % (1 << 24);
+    let integer = packed >> 24;
+
+    if mysql_type == MYSQL_TYPE_TIME {
+        return format!(
+            "{}{:02}:{:02}:{:02}.{:06}",
+            sign,
+            (integer >> 12) % (1 << 10),
+            (integer >> 6) % (1 << 6),
+            integer % (1 << 6),
+            micros
+        );
+    }
+
+    let year_month_day = integer >> 17;
+    let year_month = year_month_day >> 5;
+    let date = format!(
+        "{:04}-{:02}-{:02}",
+        year_month / 13,
+        year_month % 13,
+        year_month_day % (1 << 5)
+    );
+    if mysql_type == MYSQL_TYPE_DATE {
+        return date;
+    }
+
+    let hour_minute_second = integer % (1 << 17);
+    format!(
+        "{} {:02}:{:02}:{:02}.{:06}",
+        date,
+        hour_minute_second >> 12,
+        (hour_minute_second >> 6) % (1 << 6),
+        hour_minute_second % (1 << 6),
+        micros
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    // {"a": 1, "b": "x"}
+    fn small_object_document() -> Vec<u8> {
+        [
+            // Type, count and size
+            &[SMALL_OBJECT, 2, 0, 24, 0][..],
+            // Key entries
+            &[18, 0, 1, 0, 19, 0, 1, 0],
+            // Value entries
+            &[INT16, 1, 0, STRING, 20, 0],
+            b"ab",
+            // String value and padding
+            &[1, b'x', 0, 0],
+        ]
+        .concat()
+    }
+
+    // [true, 70000, -1.5]
+    fn large_array_document() -> Vec<u8> {
+        let mut document = vec![LARGE_ARRAY, 3, 0, 0, 0, 31, 0, 0, 0];
+        document.extend([LITERAL, LITERAL_TRUE, 0, 0, 0]);
+        document.push(INT32);
+        document.extend(70000i32.to_le_bytes());
+        document.extend([DOUBLE, 23, 0, 0, 0]);
+        document.extend((-1.5f64).to_le_bytes());
+        document
+    }
+
+    fn opaque(mysql_type: u8, data: &[u8]) -> Vec<u8> {
+        let mut document = vec![OPAQUE, mysql_type, data.len() as u8];
+        document.extend(data);
+        document
+    }
+
+    #[test]
+    fn decodes_objects() {
+        assert_eq!(
+            decode_binary_json(&small_object_document()).unwrap(),
+            json!({ "a": 1, "b": "x" })
+        );
+
+        // {"k": null}
+        let large_object = [
+            &[LARGE_OBJECT, 1, 0, 0, 0, 20, 0, 0, 0][..],
+            &[19, 0, 0, 0, 1, 0],
+            &[LITERAL, LITERAL_NULL, 0, 0, 0],
+            b"k",
+        ]
+        .concat();
+        assert_eq!(
+            decode_binary_json(&large_object).unwrap(),
+            json!({ "k": null })
+        );
+        assert_eq!(
+            decode_binary_json(&[SMALL_OBJECT, 0, 0, 4, 0]).unwrap(),
+            json!({})
+        );
+    }
+
+    #[test]
+    fn decodes_arrays() {
+        // [1, "ab"]
+        let small_array = [
+            &[SMALL_ARRAY, 2, 0, 13, 0][..],
+            &[UINT16, 1, 0, STRING, 10, 0],
+            &[2, b'a', b'b'],
+        ]
+        .concat();
+        assert_eq!(decode_binary_json(&small_array).unwrap(), json!([1, "ab"]));
+        assert_eq!(
+            decode_binary_json(&large_array_document()).unwrap(),
+            json!([true, 70000, -1.5])
+        );
+    }
+
+    #[test]
+    fn decodes_scalars() {
+        assert_eq!(decode_binary_json(&[]).unwrap(), serde_json::Value::Null);
+        assert_eq!(
+            decode_binary_json(&[LITERAL, LITERAL_FALSE]).unwrap(),
+            json!(false)
+        );
+        assert_eq!(decode_binary_json(&[INT16, 0xff, 0xff]).unwrap(), json!(-1));
+        assert_eq!(
+            decode_binary_json(&[UINT16, 0xff, 0xff]).unwrap(),
+            json!(65535)
+        );
+        assert_eq!(
+            decode_binary_json(&[INT32, 0, 0, 0, 0x80]).unwrap(),
+            json!(i32::MIN)
+        );
+        assert_eq!(
+            decode_binary_json(&[UINT32, 0xff, 0xff, 0xff, 0xff]).unwrap(),
+            json!(u32::MAX)
+        );
+        let mut int64 = vec![INT64];
+        int64.extend(i64::MIN.to_le_bytes());
+        assert_eq!(decode_binary_json(&int64).unwrap(), json!(i64::MIN));
+        let mut uint64 = vec![UINT64];
+        uint64.extend(u64::MAX.to_le_bytes());
+        assert_eq!(decode_binary_json(&uint64).unwrap(), json!(u64::MAX));
+        let mut double = vec![DOUBLE];
+        double.extend(0.25f64.to_le_bytes());
+        assert_eq!(decode_binary_json(&double).unwrap(), json!(0.25));
+        assert_eq!(
+            decode_binary_json(&[STRING, 3, b'a', b'b', b'c']).unwrap(),
+            json!("abc")
+        );
+
+        // A string of 200 bytes needs two length bytes
+        let mut long_string = vec![STRING, 0xc8, 0x01];
+        long_string.extend([b'z'; 200]);
+        assert_eq!(
+            decode_binary_json(&long_string).unwrap(),
+            json!("z".repeat(200))
+        );
+    }
+
+    #[test]
+    fn decodes_opaque_values() {
+        // DECIMAL(5,1) 1234.5 and its negation
+        let decimal = [5, 1, 0x84, 0xd2, 0x05];
+        assert_eq!(
+            decode_binary_json(&opaque(MYSQL_TYPE_NEWDECIMAL, &decimal))
+                .unwrap()
+                .to_string(),
+            "1234.5"
+        );
+        let negative = decimal.map(|byte| !byte);
+        assert_eq!(
+            decode_binary_json(&opaque(
+                MYSQL_TYPE_NEWDECIMAL,
+                &[5, 1, negative[2], negative[3], negative[4]]
+            ))
+            .unwrap()
+            .to_string(),
+            "-1234.5"
+        );
+
+        let year_month_day: i64 = ((2024 * 13 + 1) << 5) | 2;
+        let hour_minute_second: i64 = (3 << 12) | (4 << 6) | 5;
+        let datetime = (((year_month_day << 17) | hour_minute_second) << 24) | 6;
+        assert_eq!(
+            decode_binary_json(&opaque(MYSQL_TYPE_DATETIME, &datetime.to_le_bytes())).unwrap(),
+            json!("2024-01-02 03:04:05.000006")
+        );
+        assert_eq!(
+            decode_binary_json(&opaque(MYSQL_TYPE_DATE, &datetime.to_le_bytes())).unwrap(),
+            json!("2024-01-02")
+        );
+        let time: i64 = -(((12 << 12) | (34 << 6) | 56) << 24);
+        assert_eq!(
+            decode_binary_json(&opaque(MYSQL_TYPE_TIME, &time.to_le_bytes())).unwrap(),
+            json!("-12:34:56.000000")
+        );
+
+        // Blobs and opaque values that don't decode are written as base64
+        assert_eq!(
+            decode_binary_json(&opaque(252, &[1, 2])).unwrap(),
+            json!("base64:type252:AQI=")
+        );
+        assert_eq!(
+            decode_binary_json(&opaque(MYSQL_TYPE_DATETIME, &[1, 2])).unwrap(),
+            json!("base64:type12:AQI=")
+        );
+    }
+
+    #[test]
+    fn reports_truncated_documents() {
+        let mut uint64 = vec![UINT64];
+        uint64.extend(u64::MAX.to_le_bytes());
+        let documents = [
+            small_object_document(),
+            large_array_document(),
+            uint64,
+            vec![STRING, 3, b'a', b'b', b'c'],
+            opaque(MYSQL_TYPE_NEWDECIMAL, &[5, 1, 0x84, 0xd2, 0x05]),
+        ];
+        for document in documents {
+            for length in 1..document.len() {
+                let error = decode_binary_json(&document[..length]).unwrap_err();
+                assert!(
+                    matches!(error.kind, BinaryJsonErrorKind::Truncated),
+                    "{:?} decoded with {:?}",
+                    &document[..length],
+                    error
+                );
+            }
+        }
+
+        // Offsets pointing past the container
+        let mut dangling = small_object_document();
+        dangling[17] = 200;
+        assert!(matches!(
+            decode_binary_json(&dangling).unwrap_err().kind,
+            BinaryJsonErrorKind::Truncated
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_values() {
+        assert!(matches!(
+            decode_binary_json(&[0x0d]).unwrap_err().kind,
+            BinaryJsonErrorKind::UnknownValueType(0x0d)
+        ));
+        assert!(matches!(
+            decode_binary_json(&[LITERAL, 0x07]).unwrap_err().kind,
+            BinaryJsonErrorKind::UnknownValueType(0x07)
+        ));
+        assert!(matches!(
+            decode_binary_json(&[STRING, 1, 0xff]).unwrap_err().kind,
+            BinaryJsonErrorKind::InvalidString(_)
+        ));
+    }
+}
@@ -0,0 +1,345 @@
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    future::Future,
+    process::ExitCode,
+};
+
+use tokio::{
+    select,
+    sync::watch,
+    task::{AbortHandle, JoinError, JoinSet},
+};
+
+use crate::{
+    console_stream_producer::ConsoleStreamProducerError,
+    kafka_stream_producer::KafkaStreamProducerError,
+    shutdown_signal::ShutdownSignal,
+    table_row_change_json_converter::{RowJsonConverterError, RowJsonConverterErrorKind},
+    vitess_snapshot::{VitessSnapshotError, VitessSnapshotErrorKind},
+    vitess_vstream_listener::{VstreamListenerError, VstreamListenerErrorKind},
+};
+
+// Process exit codes, so alerting can tell what broke without parsing the logs. Startup errors
+// returned from `main` exit with 1 and clap exits with 2 on bad arguments.
+const EXIT_SOURCE_FAILED: u8 = 3;
+const EXIT_CONVERSION_FAILED: u8 = 4;
+const EXIT_SINK_FAILED: u8 = 5;
+const EXIT_SNAPSHOT_FAILED: u8 = 6;
+const EXIT_STAGE_PANICKED: u8 = 7;
+const EXIT_SHUTDOWN_FORCED: u8 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PipelineStage {
+    Snapshot,
+    Listener,
+    Converter,
+    Sink,
+}
+
+impl PipelineStage {
+    // The snapshot only feeds the listener, there is nothing to drain when it is stopped
+    fn drains_on_shutdown(self) -> bool {
+        self != PipelineStage::Snapshot
+    }
+}
+
+impl Display for PipelineStage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PipelineStage::Snapshot => write!(f, "snapshot"),
+            PipelineStage::Listener => write!(f, "vstream listener"),
+            PipelineStage::Converter => write!(f, "json converter"),
+            PipelineStage::Sink => write!(f, "sink"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum StageError {
+    Snapshot(VitessSnapshotError),
+    Listener(VstreamListenerError),
+    Converter(RowJsonConverterError),
+    ConsoleSink(ConsoleStreamProducerError),
+    KafkaSink(KafkaStreamProducerError),
+    Panicked(JoinError),
+}
+
+impl StageError {
+    fn as_error(&self) -> &(dyn Error + 'static) {
+        match self {
+            StageError::Snapshot(e) => e,
+            StageError::Listener(e) => e,
+            StageError::Converter(e) => e,
+            StageError::ConsoleSink(e) => e,
+            StageError::KafkaSink(e) => e,
+            StageError::Panicked(e) => e,
+        }
+    }
+
+    // A stage whose neighbour went away fails on its next send, that is a consequence of the
+    // neighbour's failure rather than a failure of its own
+    fn is_disconnected(&self) -> bool {
+        matches!(
+            self,
+            StageError::Snapshot(VitessSnapshotError {
+                kind: VitessSnapshotErrorKind::SendFailed(_)
+                    | VitessSnapshotErrorKind::ListenerStopped(_),
+                ..
+            }) | StageError::Listener(VstreamListenerError {
+                kind: VstreamListenerErrorKind::SendFailed(_),
+                ..
+            }) | StageError::Converter(RowJsonConverterError {
+                kind: RowJsonConverterErrorKind::SendFailed(_),
+                ..
+            })
+        )
+    }
+
+    fn exit_code(&self) -> u8 {
+        match self {
+            StageError::Snapshot(_) => EXIT_SNAPSHOT_FAILED,
+            StageError::Listener(_) => EXIT_SOURCE_FAILED,
+            StageError::Converter(_) => EXIT_CONVERSION_FAILED,
+            StageError::ConsoleSink(_) | StageError::KafkaSink(_) => EXIT_SINK_FAILED,
+            StageError::Panicked(_) => EXIT_STAGE_PANICKED,
+        }
+    }
+}
+
+impl From<VitessSnapshotError> for StageError {
+    fn from(e: VitessSnapshotError) -> Self {
+        StageError::Snapshot(e)
+    }
+}
+
+impl From<VstreamListenerError> for StageError {
+    fn from(e: VstreamListenerError) -> Self {
+        StageError::Listener(e)
+    }
+}
+
+impl From<RowJsonConverterError> for StageError {
+    fn from(e: RowJsonConverterError) -> Self {
+        StageError::Converter(e)
+    }
+}
+
+impl From<ConsoleStreamProducerError> for StageError {
+    fn from(e: ConsoleStreamProducerError) -> Self {
+        StageError::ConsoleSink(e)
+    }
+}
+
+impl From<KafkaStreamProducerError> for StageError {
+    fn from(e: KafkaStreamProducerError) -> Self {
+        StageError::KafkaSink(e)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Supervised {
+    Stopped(ExitCode),
+    /// A second shutdown signal arrived while the stages were still draining
+    Forced(&'static str),
+}
+
+/// Runs the pipeline stages and stops all of them once one fails or a shutdown signal arrives.
+pub(crate) struct PipelineSupervisor {
+    stages: JoinSet<(PipelineStage, Result<(), StageError>)>,
+    // Stages that are aborted instead of drained on shutdown
+    abort_on_shutdown: Vec<AbortHandle>,
+    shutdown: watch::Sender<bool>,
+}
+
+impl PipelineSupervisor {
+    pub(crate) fn new(shutdown: watch::Sender<bool>) -> PipelineSupervisor {
+        PipelineSupervisor {
+            stages: JoinSet::new(),
+            abort_on_shutdown: Vec::new(),
+            shutdown,
+        }
+    }
+
+    pub(crate) fn spawn<F, E>(&mut self, stage: PipelineStage, future: F)
+    where
+        F: Future<Output = Result<(), E>> + Send + 'static,
+        E: Into<StageError> + Send + 'static,
+    {
+        let handle = tokio::task::spawn(future);
+        if !stage.drains_on_shutdown() {
+            self.abort_on_shutdown.push(handle.abort_handle());
+        }
+        self.supervise(stage, handle);
+    }
+
+    pub(crate) fn spawn_blocking<F, E>(&mut self, stage: PipelineStage, function: F)
+    where
+        F: FnOnce() -> Result<(), E> + Send + 'static,
+        E: Into<StageError> + Send + 'static,
+    {
+        // Blocking tasks can't be aborted, they have to stop on their own
+        let handle = tokio::task::spawn_blocking(function);
+        self.supervise(stage, handle);
+    }
+
+    // Awaiting the stage from a separate task turns its panic into a value we can attribute
+    fn supervise<E>(&mut self, stage: PipelineStage, handle: tokio::task::JoinHandle<Result<(), E>>)
+    where
+        E: Into<StageError> + Send + 'static,
+    {
+        self.stages.spawn(async move {
+            let result = match handle.await {
+                Ok(result) => result.map_err(Into::into),
+                Err(e) if e.is_cancelled() => Ok(()),
+                Err(e) => Err(StageError::Panicked(e)),
+            };
+            (stage, result)
+        });
+    }
+
+    /// Waits for every stage to stop and returns the exit code of the failure that brought the
+    /// pipeline down. A second shutdown signal exits the process right away, returning would drop
+    /// the runtime and wait for the blocking stages to drain anyway.
+    pub(crate) async fn run(self, shutdown_signal: &mut ShutdownSignal) -> ExitCode {
+        match self
+            .wait_for_stages(async || shutdown_signal.recv().await)
+            .await
+        {
+            Supervised::Stopped(exit_code) => exit_code,
+            Supervised::Forced(signal) => {
+                log::warn!("Received {} again, exiting without draining", signal);
+                std::process::exit(EXIT_SHUTDOWN_FORCED.into());
+            }
+        }
+    }
+
+    async fn wait_for_stages(
+        mut self,
+        mut next_signal: impl AsyncFnMut() -> &'static str,
+    ) -> Supervised {
+        let mut failures = Vec::new();
+        let mut signalled = false;
+        loop {
+            select! {
+                joined = self.stages.join_next() => {
+                    let Some(joined) = joined else {
+                        break;
+                    };
+                    let (stage, result) =
+                        joined.expect("Supervising task should not panic or be aborted");
+                    let failed = result.is_err();
+                    match result {
+                        Ok(()) => log::info!("The {} stage stopped", stage),
+                        Err(e) => {
+                            log::error!("The {} stage failed: {}", stage, error_chain(e.as_error()));
+                            failures.push(e);
+                        }
+                    }
+                    // The snapshot is the only stage that finishes while the pipeline keeps going
+                    if failed || stage.drains_on_shutdown() {
+                        self.shut_down();
+                    }
+                }
+                signal = next_signal() => {
+                    if signalled {
+                        return Supervised::Forced(signal);
+                    }
+                    log::info!("Received {}, draining the pipeline...", signal);
+                    signalled = true;
+                    self.shut_down();
+                }
+            }
+        }
+
+        match failures
+            .iter()
+            .find(|e| !e.is_disconnected())
+            .or(failures.first())
+        {
+            Some(e) => Supervised::Stopped(ExitCode::from(e.exit_code())),
+            None => {
+                log::info!("Vitess replicator stopped");
+                Supervised::Stopped(ExitCode::SUCCESS)
+            }
+        }
+    }
+
+    fn shut_down(&mut self) {
+        self.shutdown.send_replace(true);
+        for abort_handle in self.abort_on_shutdown.drain(..) {
+            abort_handle.abort();
+        }
+    }
+}
+
+fn error_chain(error: &(dyn Error + 'static)) -> String {
+    let mut chain = error.to_string();
+    let mut source = error.source();
+    while let Some(e) = source {
+        chain.push_str(": ");
+        chain.push_str(&e.to_string());
+        source = e.source();
+    }
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{runtime::Runtime, sync::mpsc};
+
+    fn runtime() -> Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn stops_waiting_on_a_second_signal() {
+        let runtime = runtime();
+        let (shutdown, _) = watch::channel(false);
+        let (release, stuck) = std::sync::mpsc::channel::<()>();
+        let (signals, mut received) = mpsc::unbounded_channel();
+        signals.send("SIGTERM").unwrap();
+        signals.send("SIGINT").unwrap();
+
+        let supervised = runtime.block_on(async {
+            let mut supervisor = PipelineSupervisor::new(shutdown);
+            // A sink stuck flushing ignores the shutdown
+            supervisor.spawn_blocking(PipelineStage::Sink, move || {
+                let _ = stuck.recv();
+                Ok::<_, KafkaStreamProducerError>(())
+            });
+            supervisor
+                .wait_for_stages(async || received.recv().await.unwrap())
+                .await
+        });
+
+        assert_eq!(supervised, Supervised::Forced("SIGINT"));
+        drop(release);
+    }
+
+    #[test]
+    fn drains_on_the_first_signal() {
+        let runtime = runtime();
+        let (shutdown, _) = watch::channel(false);
+        let mut stopping = shutdown.subscribe();
+        let (signals, mut received) = mpsc::unbounded_channel();
+        signals.send("SIGTERM").unwrap();
+
+        let supervised = runtime.block_on(async {
+            let mut supervisor = PipelineSupervisor::new(shutdown);
+            supervisor.spawn(PipelineStage::Listener, async move {
+                let _ = stopping.wait_for(|stopping| *stopping).await;
+                Ok::<_, KafkaStreamProducerError>(())
+            });
+            supervisor
+                .wait_for_stages(async || received.recv().await.unwrap())
+                .await
+        });
+
+        assert_eq!(supervised, Supervised::Stopped(ExitCode::SUCCESS));
+    }
+}
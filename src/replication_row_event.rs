@@ -1,6 +1,10 @@
 use crate::{
-    vitess_grpc::{binlogdata::RowChange, query::Row},
+    vitess_grpc::{
+        binlogdata::{RowChange, VGtid},
+        query::Row,
+    },
     vitess_schema::TableName,
+    vitess_schema_registry::SchemaVersion,
     vitess_shards::KeyspaceName,
 };
 
@@ -24,8 +28,56 @@ impl From<RowChange> for ReplicationRowEvent {
     }
 }
 
+impl ReplicationRowEvent {
+    pub(crate) fn into_snapshot_read(self) -> Self {
+        match self {
+            ReplicationRowEvent::Insert(row) => ReplicationRowEvent::SnapshotRead(row),
+            event => event,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TransactionContext {
+    /// GTID of the transaction on its shard, like `uuid:42`
+    pub(crate) id: String,
+    pub(crate) shard: String,
+    /// Seconds since the epoch at which the event was written to the binlog
+    pub(crate) timestamp: i64,
+    /// Position of the row within its transaction, starting at zero
+    pub(crate) sequence: u64,
+}
+
 pub(crate) struct ReplicationRowEventEnvelope {
     pub(crate) keyspace: KeyspaceName,
     pub(crate) table: TableName,
     pub(crate) event: ReplicationRowEvent,
+    pub(crate) transaction: TransactionContext,
+    /// Version of the table schema in the registry the row has to be decoded with
+    pub(crate) schema_version: SchemaVersion,
+}
+
+/// Follows the last row of a transaction so consumers can apply it as a whole
+pub(crate) struct TransactionBoundary {
+    pub(crate) keyspace: KeyspaceName,
+    pub(crate) shard: String,
+    pub(crate) id: String,
+    pub(crate) timestamp: i64,
+    pub(crate) row_count: u64,
+}
+
+pub(crate) struct SchemaChangeEnvelope {
+    pub(crate) keyspace: KeyspaceName,
+    pub(crate) shard: String,
+    /// Position of the shard right after the statement was applied
+    pub(crate) position: String,
+    pub(crate) timestamp: i64,
+    pub(crate) statement: String,
+}
+
+pub(crate) enum ReplicationMessage {
+    RowEvent(ReplicationRowEventEnvelope),
+    SchemaChange(SchemaChangeEnvelope),
+    TransactionCommitted(TransactionBoundary),
+    Checkpoint(VGtid),
 }
@@ -1,18 +1,27 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     error::Error,
     fmt::{self, Display, Formatter},
-    sync::mpsc::{Receiver, RecvError, SendError, Sender},
+    sync::mpsc::{SendError, SyncSender},
 };
 
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use serde_json::Map;
+use tokio::sync::mpsc::Receiver;
 
 use crate::{
-    replication_row_event::{ReplicationRowEvent, ReplicationRowEventEnvelope},
+    command_line_args::{Args, RowEnvelope, RowErrorPolicy},
+    debezium_envelope::{debezium_envelope, debezium_key},
+    replication_row_event::{
+        ReplicationMessage, ReplicationRowEvent, ReplicationRowEventEnvelope, SchemaChangeEnvelope,
+        TransactionBoundary, TransactionContext,
+    },
     table_row_deserializer::{
-        DeserializeRowError, deserialize_row_values, transform_string_to_json_value,
+        DeserializeRowError, ValueConversionOptions, raw_row_values, transform_value_to_json,
     },
+    vitess_grpc::{binlogdata::VGtid, query::Row},
     vitess_schema::{TableName, VitessSchema},
+    vitess_schema_registry::{SchemaRegistry, SchemaVersion},
     vitess_shards::KeyspaceName,
 };
 
@@ -31,7 +40,6 @@ impl Display for RowJsonConverterError {
 impl Error for RowJsonConverterError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match &self.kind {
-            RowJsonConverterErrorKind::RecvFailed(e) => Some(e),
             RowJsonConverterErrorKind::TableSchemaNotFound(e) => Some(e),
             RowJsonConverterErrorKind::ConvertToJsonFailed(e) => Some(e),
             RowJsonConverterErrorKind::SendFailed(e) => Some(e),
@@ -43,13 +51,14 @@ impl Error for RowJsonConverterError {
 pub(crate) struct MissingTableSchemaError {
     pub(crate) keyspace: Box<KeyspaceName>,
     pub(crate) table: Box<TableName>,
+    pub(crate) schema_version: SchemaVersion,
 }
 impl Display for MissingTableSchemaError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "Cannot find schema for table {} in keyspace {}. Did you configure this table as one of the ones to watch?",
-            self.table, self.keyspace
+            "Cannot find schema version {} for table {} in keyspace {}. Did you configure this table as one of the ones to watch?",
+            self.schema_version, self.table, self.keyspace
         )
     }
 }
@@ -57,82 +66,485 @@ impl Error for MissingTableSchemaError {}
 
 #[derive(Debug)]
 pub enum RowJsonConverterErrorKind {
-    RecvFailed(RecvError),
     TableSchemaNotFound(MissingTableSchemaError),
     ConvertToJsonFailed(DeserializeRowError),
-    SendFailed(SendError<serde_json::Value>),
+    SendFailed(Box<SendError<JsonStreamMessage>>),
+}
+
+pub(crate) struct JsonRowRecord {
+    pub(crate) keyspace: KeyspaceName,
+    pub(crate) table: TableName,
+    pub(crate) key: Option<serde_json::Value>,
+    /// `None` for a tombstone that lets compacted topics drop the key
+    pub(crate) value: Option<serde_json::Value>,
+    pub(crate) transaction: TransactionContext,
+}
+
+pub(crate) struct JsonTransactionRecord {
+    pub(crate) keyspace: KeyspaceName,
+    pub(crate) id: String,
+    pub(crate) value: serde_json::Value,
+}
+
+pub(crate) struct JsonSchemaChangeRecord {
+    pub(crate) keyspace: KeyspaceName,
+    pub(crate) value: serde_json::Value,
+}
+
+pub(crate) struct JsonDeadLetterRecord {
+    pub(crate) keyspace: KeyspaceName,
+    pub(crate) table: TableName,
+    pub(crate) value: serde_json::Value,
+}
+
+pub(crate) enum JsonStreamMessage {
+    Row(JsonRowRecord),
+    DeadLetter(JsonDeadLetterRecord),
+    SchemaChange(JsonSchemaChangeRecord),
+    TransactionCommitted(JsonTransactionRecord),
+    Checkpoint(VGtid),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RowErrorPolicies {
+    default: RowErrorPolicy,
+    tables: HashMap<TableName, RowErrorPolicy>,
+}
+
+impl From<&Args> for RowErrorPolicies {
+    fn from(args: &Args) -> Self {
+        RowErrorPolicies {
+            default: args.row_error_policy,
+            tables: args.table_row_error_policy.iter().cloned().collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RowEnvelopeOptions {
+    envelope: RowEnvelope,
+    debezium_schema: bool,
+    changed_columns_only: bool,
+    split_primary_key_updates: bool,
+}
+
+impl From<&Args> for RowEnvelopeOptions {
+    fn from(args: &Args) -> Self {
+        RowEnvelopeOptions {
+            envelope: args.row_envelope,
+            debezium_schema: args.debezium_schema,
+            changed_columns_only: args.changed_columns_only,
+            split_primary_key_updates: args.split_primary_key_updates,
+        }
+    }
+}
+
+impl RowErrorPolicies {
+    fn for_table(&self, table: &TableName) -> RowErrorPolicy {
+        self.tables.get(table).copied().unwrap_or(self.default)
+    }
 }
 
 pub(crate) fn start_row_change_json_converter(
-    incoming_rows: Receiver<ReplicationRowEventEnvelope>,
-    outgoing_rows: Sender<serde_json::Value>,
-    schemas: HashMap<TableName, VitessSchema>,
+    mut incoming_rows: Receiver<ReplicationMessage>,
+    outgoing_rows: SyncSender<JsonStreamMessage>,
+    schemas: SchemaRegistry,
+    error_policies: RowErrorPolicies,
+    conversion_options: ValueConversionOptions,
+    envelope_options: RowEnvelopeOptions,
 ) -> Result<(), RowJsonConverterError> {
     loop {
-        let event_envelope = incoming_rows.recv().map_err(|e| RowJsonConverterError {
-            kind: RowJsonConverterErrorKind::RecvFailed(e),
-        })?;
+        // The listener hangs up once it stopped reading, everything it sent has been converted
+        let Some(message) = incoming_rows.blocking_recv() else {
+            return Ok(());
+        };
+
+        let event_envelope = match message {
+            ReplicationMessage::RowEvent(event_envelope) => event_envelope,
+            ReplicationMessage::Checkpoint(vgtid) => {
+                outgoing_rows
+                    .send(JsonStreamMessage::Checkpoint(vgtid))
+                    .map_err(|e| RowJsonConverterError {
+                        kind: RowJsonConverterErrorKind::SendFailed(Box::new(e)),
+                    })?;
+                continue;
+            }
+            ReplicationMessage::SchemaChange(schema_change) => {
+                outgoing_rows
+                    .send(JsonStreamMessage::SchemaChange(schema_change_to_json(
+                        schema_change,
+                    )))
+                    .map_err(|e| RowJsonConverterError {
+                        kind: RowJsonConverterErrorKind::SendFailed(Box::new(e)),
+                    })?;
+                continue;
+            }
+            ReplicationMessage::TransactionCommitted(boundary) => {
+                outgoing_rows
+                    .send(JsonStreamMessage::TransactionCommitted(
+                        transaction_boundary_to_json(boundary),
+                    ))
+                    .map_err(|e| RowJsonConverterError {
+                        kind: RowJsonConverterErrorKind::SendFailed(Box::new(e)),
+                    })?;
+                continue;
+            }
+        };
 
         log::info!("Deserializing row, finding schema...");
 
-        let schema = schemas
-            .get(&event_envelope.table)
+        let versioned_schema = schemas
+            .get(&event_envelope.table, event_envelope.schema_version)
             .ok_or(RowJsonConverterError {
                 kind: RowJsonConverterErrorKind::TableSchemaNotFound(MissingTableSchemaError {
                     keyspace: Box::new(event_envelope.keyspace.clone()),
                     table: Box::new(event_envelope.table.clone()),
+                    schema_version: event_envelope.schema_version,
                 }),
             })?;
+        log::debug!(
+            "Decoding row with schema version {} of {} registered at {:?}",
+            versioned_schema.version,
+            event_envelope.table,
+            versioned_schema.position
+        );
+        let schema = &versioned_schema.schema;
 
-        let json =
-            row_event_to_json(event_envelope.event, schema).map_err(|e| RowJsonConverterError {
-                kind: RowJsonConverterErrorKind::ConvertToJsonFailed(e),
-            })?;
+        let records = match row_event_to_records(
+            &event_envelope,
+            schema,
+            &conversion_options,
+            envelope_options,
+        ) {
+            Ok(records) => records,
+            Err(e) => match error_policies.for_table(&event_envelope.table) {
+                RowErrorPolicy::Fail => {
+                    return Err(RowJsonConverterError {
+                        kind: RowJsonConverterErrorKind::ConvertToJsonFailed(e),
+                    });
+                }
+                RowErrorPolicy::Skip => {
+                    log::warn!(
+                        "Skipping row of {} in transaction {}, column {} `{}`: {}",
+                        event_envelope.table,
+                        event_envelope.transaction.id,
+                        e.column_number,
+                        e.column_name,
+                        e.source().map(|e| e.to_string()).unwrap_or_default()
+                    );
+                    continue;
+                }
+                RowErrorPolicy::DeadLetter => {
+                    log::warn!(
+                        "Dead-lettering row of {} in transaction {}, column {} `{}`: {}",
+                        event_envelope.table,
+                        event_envelope.transaction.id,
+                        e.column_number,
+                        e.column_name,
+                        e.source().map(|e| e.to_string()).unwrap_or_default()
+                    );
+                    outgoing_rows
+                        .send(JsonStreamMessage::DeadLetter(dead_letter_to_json(
+                            &event_envelope,
+                            &e,
+                        )))
+                        .map_err(|e| RowJsonConverterError {
+                            kind: RowJsonConverterErrorKind::SendFailed(Box::new(e)),
+                        })?;
+                    continue;
+                }
+            },
+        };
 
-        outgoing_rows
-            .send(json)
-            .map_err(|e| RowJsonConverterError {
-                kind: RowJsonConverterErrorKind::SendFailed(e),
-            })?;
+        for record in records {
+            outgoing_rows
+                .send(JsonStreamMessage::Row(record))
+                .map_err(|e| RowJsonConverterError {
+                    kind: RowJsonConverterErrorKind::SendFailed(Box::new(e)),
+                })?;
+        }
     }
 }
 
-fn row_event_to_json(
-    row_event: ReplicationRowEvent,
+// Records for a row change, an update of the primary key becomes a delete of the old key, a
+// tombstone for it and an insert of the new key when configured
+fn row_event_to_records(
+    envelope: &ReplicationRowEventEnvelope,
     schema: &VitessSchema,
-) -> Result<serde_json::Value, DeserializeRowError> {
-    let mut data: Map<String, serde_json::Value> = Map::new();
-    data.insert(
-        "op".to_string(),
-        serde_json::Value::String(event_to_op_name(&row_event)),
-    );
-
-    let row = match row_event {
-        ReplicationRowEvent::Insert(row) => row,
-        ReplicationRowEvent::Update { before: _, after } => after,
-        ReplicationRowEvent::Delete(row) => row,
-        ReplicationRowEvent::SnapshotRead(row) => row,
+    options: &ValueConversionOptions,
+    envelope_options: RowEnvelopeOptions,
+) -> Result<Vec<JsonRowRecord>, DeserializeRowError> {
+    let to_record = |event: ReplicationRowEvent| {
+        let envelope = ReplicationRowEventEnvelope {
+            keyspace: envelope.keyspace.clone(),
+            table: envelope.table.clone(),
+            event,
+            transaction: envelope.transaction.clone(),
+            schema_version: envelope.schema_version,
+        };
+        row_event_to_record(&envelope, schema, options, envelope_options)
     };
 
-    let field_value_strings = deserialize_row_values(row, schema)?;
-    for (column_number, (value, (field_name, field))) in field_value_strings
+    if envelope_options.split_primary_key_updates
+        && let ReplicationRowEvent::Update { before, after } = &envelope.event
+        && primary_key_changed(before, after, schema)
+    {
+        let delete = to_record(ReplicationRowEvent::Delete(before.clone()))?;
+        let insert = to_record(ReplicationRowEvent::Insert(after.clone()))?;
+        let tombstone = JsonRowRecord {
+            keyspace: delete.keyspace.clone(),
+            table: delete.table.clone(),
+            key: delete.key.clone(),
+            value: None,
+            transaction: delete.transaction.clone(),
+        };
+        return Ok(vec![delete, tombstone, insert]);
+    }
+
+    Ok(vec![row_event_to_record(
+        envelope,
+        schema,
+        options,
+        envelope_options,
+    )?])
+}
+
+fn row_event_to_record(
+    envelope: &ReplicationRowEventEnvelope,
+    schema: &VitessSchema,
+    options: &ValueConversionOptions,
+    envelope_options: RowEnvelopeOptions,
+) -> Result<JsonRowRecord, DeserializeRowError> {
+    let (key, value) = row_event_to_json(envelope, schema, options, envelope_options)?;
+    Ok(JsonRowRecord {
+        keyspace: envelope.keyspace.clone(),
+        table: envelope.table.clone(),
+        key,
+        value: Some(value),
+        transaction: envelope.transaction.clone(),
+    })
+}
+
+// Vstream encodes equal values the same way, so comparing the raw primary key values is enough
+fn primary_key_changed(before: &Row, after: &Row, schema: &VitessSchema) -> bool {
+    let before_values = raw_row_values(before);
+    let after_values = raw_row_values(after);
+    schema
+        .primary_key_fields()
+        .into_iter()
+        .any(|(position, _, _)| before_values.get(position) != after_values.get(position))
+}
+
+// Key and value of the record for a row change
+fn row_event_to_json(
+    envelope: &ReplicationRowEventEnvelope,
+    schema: &VitessSchema,
+    options: &ValueConversionOptions,
+    envelope_options: RowEnvelopeOptions,
+) -> Result<(Option<serde_json::Value>, serde_json::Value), DeserializeRowError> {
+    let (before_row, after_row) = event_rows(&envelope.event);
+    let mut before = before_row
+        .map(|row| row_to_json(row, schema, options))
+        .transpose()?;
+    let mut after = after_row
+        .map(|row| row_to_json(row, schema, options))
+        .transpose()?;
+    let key = after
+        .as_ref()
+        .or(before.as_ref())
+        .and_then(|row| primary_key_to_json(row, schema));
+    if envelope_options.changed_columns_only
+        && let (Some(before), Some(after)) = (&mut before, &mut after)
+    {
+        retain_changed_columns(before, after, schema);
+    }
+
+    match envelope_options.envelope {
+        RowEnvelope::Flat => {
+            let mut data: Map<String, serde_json::Value> = Map::new();
+            data.insert(
+                "op".to_string(),
+                serde_json::Value::String(event_to_op_name(&envelope.event)),
+            );
+            // Inserts and deletes write their row as the columns. Updates nest both rows, a column
+            // named `before` would collide with the old row otherwise.
+            match (before, after) {
+                (Some(before), Some(after)) => {
+                    data.insert("before".to_string(), serde_json::Value::Object(before));
+                    data.insert("after".to_string(), serde_json::Value::Object(after));
+                }
+                (None, Some(row)) | (Some(row), None) => data.extend(row),
+                (None, None) => unreachable!("Row event has a before or after row"),
+            }
+
+            Ok((key, serde_json::Value::Object(data)))
+        }
+        RowEnvelope::Debezium => {
+            let key = key.map(|key| {
+                debezium_key(
+                    envelope,
+                    key,
+                    schema,
+                    options,
+                    envelope_options.debezium_schema,
+                )
+            });
+            let value = debezium_envelope(
+                envelope,
+                before,
+                after,
+                schema,
+                options,
+                envelope_options.debezium_schema,
+            );
+
+            Ok((key, value))
+        }
+    }
+}
+
+// Drops the columns an update left unchanged from both images, primary key columns are kept so
+// the row can still be identified
+fn retain_changed_columns(
+    before: &mut Map<String, serde_json::Value>,
+    after: &mut Map<String, serde_json::Value>,
+    schema: &VitessSchema,
+) {
+    let unchanged: HashSet<String> = after
+        .iter()
+        .filter(|(column, value)| {
+            before.get(*column) == Some(value) && !schema.primary_keys.contains(column)
+        })
+        .map(|(column, _)| column.clone())
+        .collect();
+    before.retain(|column, _| !unchanged.contains(column));
+    after.retain(|column, _| !unchanged.contains(column));
+}
+
+fn row_to_json(
+    row: &Row,
+    schema: &VitessSchema,
+    options: &ValueConversionOptions,
+) -> Result<Map<String, serde_json::Value>, DeserializeRowError> {
+    raw_row_values(row)
         .into_iter()
         .zip(schema.schema.iter())
         .enumerate()
-    {
-        data.insert(
-            field_name.clone().into(),
-            transform_string_to_json_value(
-                value,
-                &schema.table,
-                column_number,
-                &field_name,
-                &field,
-            )?,
-        );
+        .map(|(column_number, (value, (field_name, field)))| {
+            Ok((
+                field_name.clone().into(),
+                transform_value_to_json(
+                    value,
+                    &schema.table,
+                    column_number,
+                    field_name,
+                    field,
+                    options,
+                )?,
+            ))
+        })
+        .collect()
+}
+
+// Row before and after the change, only updates have both
+fn event_rows(row_event: &ReplicationRowEvent) -> (Option<&Row>, Option<&Row>) {
+    match row_event {
+        ReplicationRowEvent::Insert(row) | ReplicationRowEvent::SnapshotRead(row) => {
+            (None, Some(row))
+        }
+        ReplicationRowEvent::Update { before, after } => (Some(before), Some(after)),
+        ReplicationRowEvent::Delete(row) => (Some(row), None),
+    }
+}
+
+// Keeps the row exactly as vstream sent it, with the schema version it has to be decoded with,
+// so it can be replayed once the conversion is fixed
+fn dead_letter_to_json(
+    envelope: &ReplicationRowEventEnvelope,
+    error: &DeserializeRowError,
+) -> JsonDeadLetterRecord {
+    let (before, after) = event_rows(&envelope.event);
+
+    JsonDeadLetterRecord {
+        value: serde_json::json!({
+            "keyspace": envelope.keyspace.to_string(),
+            "table": envelope.table.to_string(),
+            "op": event_to_op_name(&envelope.event),
+            "schema_version": envelope.schema_version,
+            "transaction": {
+                "id": envelope.transaction.id,
+                "shard": envelope.transaction.shard,
+                "ts": envelope.transaction.timestamp,
+                "sequence": envelope.transaction.sequence,
+            },
+            "error": {
+                "kind": error.kind.name(),
+                "column_number": error.column_number,
+                "column_name": error.column_name.to_string(),
+                "message": error.source().map(|e| e.to_string()),
+            },
+            "before": before.map(raw_row_to_json),
+            "after": after.map(raw_row_to_json),
+        }),
+        keyspace: envelope.keyspace.clone(),
+        table: envelope.table.clone(),
+    }
+}
+
+fn raw_row_to_json(row: &Row) -> serde_json::Value {
+    serde_json::json!({
+        "lengths": row.lengths,
+        "values": BASE64.encode(&row.values),
+    })
+}
+
+fn schema_change_to_json(schema_change: SchemaChangeEnvelope) -> JsonSchemaChangeRecord {
+    JsonSchemaChangeRecord {
+        value: serde_json::json!({
+            "keyspace": schema_change.keyspace.to_string(),
+            "shard": schema_change.shard,
+            "position": schema_change.position,
+            "ts": schema_change.timestamp,
+            "ddl": schema_change.statement,
+        }),
+        keyspace: schema_change.keyspace,
     }
+}
 
-    Ok(serde_json::Value::Object(data))
+fn transaction_boundary_to_json(boundary: TransactionBoundary) -> JsonTransactionRecord {
+    JsonTransactionRecord {
+        value: serde_json::json!({
+            "status": "END",
+            "id": boundary.id,
+            "keyspace": boundary.keyspace.to_string(),
+            "shard": boundary.shard,
+            "ts": boundary.timestamp,
+            "event_count": boundary.row_count,
+        }),
+        keyspace: boundary.keyspace,
+        id: boundary.id,
+    }
+}
+
+fn primary_key_to_json(
+    row_json: &Map<String, serde_json::Value>,
+    schema: &VitessSchema,
+) -> Option<serde_json::Value> {
+    if schema.primary_keys.is_empty() {
+        return None;
+    }
+
+    let key: Map<String, serde_json::Value> = schema
+        .primary_keys
+        .iter()
+        .map(|pk| {
+            (
+                pk.clone(),
+                row_json.get(pk).cloned().unwrap_or(serde_json::Value::Null),
+            )
+        })
+        .collect();
+    Some(serde_json::Value::Object(key))
 }
 
 fn event_to_op_name(row_event: &ReplicationRowEvent) -> String {
@@ -146,3 +558,238 @@ fn event_to_op_name(row_event: &ReplicationRowEvent) -> String {
         ReplicationRowEvent::Delete(_) => "D".to_string(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use jiff::tz::TimeZone;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        command_line_args::{
+            BinaryEncoding, JsonColumnMode, NumericMode, TemporalMode, ZeroDatePolicy,
+        },
+        vitess_grpc::query::{Field, Type},
+    };
+
+    fn schema(primary_keys: &[&str]) -> VitessSchema {
+        let field = |name: &str, column_type: Type| Field {
+            name: name.to_string(),
+            r#type: column_type.into(),
+            ..Default::default()
+        };
+        VitessSchema {
+            primary_keys: primary_keys.iter().map(|pk| pk.to_string()).collect(),
+            ..VitessSchema::from_field_event(
+                "orders".to_string().into(),
+                &[
+                    field("id", Type::Int64),
+                    field("status", Type::Varchar),
+                    field("before", Type::Int32),
+                ],
+                None,
+            )
+        }
+    }
+
+    fn row(values: &[Option<&str>]) -> Row {
+        Row {
+            lengths: values
+                .iter()
+                .map(|value| value.map_or(-1, |value| value.len() as i64))
+                .collect(),
+            values: values
+                .iter()
+                .flatten()
+                .flat_map(|value| value.bytes())
+                .collect(),
+        }
+    }
+
+    fn envelope(event: ReplicationRowEvent) -> ReplicationRowEventEnvelope {
+        ReplicationRowEventEnvelope {
+            keyspace: "commerce".to_string().into(),
+            table: "orders".to_string().into(),
+            event,
+            transaction: TransactionContext {
+                id: "uuid:42".to_string(),
+                shard: "-80".to_string(),
+                timestamp: 1_700_000_000,
+                sequence: 0,
+            },
+            schema_version: 0,
+        }
+    }
+
+    fn options() -> ValueConversionOptions {
+        ValueConversionOptions {
+            numeric_mode: NumericMode::Number,
+            binary_encoding: BinaryEncoding::Base64,
+            json_column_mode: JsonColumnMode::Nested,
+            temporal_mode: TemporalMode::Iso8601,
+            source_time_zone: TimeZone::UTC,
+            zero_date_policy: ZeroDatePolicy::Null,
+        }
+    }
+
+    fn flat() -> RowEnvelopeOptions {
+        RowEnvelopeOptions {
+            envelope: RowEnvelope::Flat,
+            debezium_schema: false,
+            changed_columns_only: false,
+            split_primary_key_updates: false,
+        }
+    }
+
+    fn map(value: serde_json::Value) -> Map<String, serde_json::Value> {
+        match value {
+            serde_json::Value::Object(map) => map,
+            value => panic!("{} is not an object", value),
+        }
+    }
+
+    #[test]
+    fn retains_changed_and_primary_key_columns() {
+        let mut before = map(json!({ "id": 1, "status": "new", "before": 5 }));
+        let mut after = map(json!({ "id": 1, "status": "paid", "before": 5 }));
+        retain_changed_columns(&mut before, &mut after, &schema(&["id"]));
+        assert_eq!(before, map(json!({ "id": 1, "status": "new" })));
+        assert_eq!(after, map(json!({ "id": 1, "status": "paid" })));
+
+        // A changed primary key is kept like any other changed column
+        let mut before = map(json!({ "id": 1, "status": "new", "before": 5 }));
+        let mut after = map(json!({ "id": 2, "status": "new", "before": 6 }));
+        retain_changed_columns(&mut before, &mut after, &schema(&["id"]));
+        assert_eq!(before, map(json!({ "id": 1, "before": 5 })));
+        assert_eq!(after, map(json!({ "id": 2, "before": 6 })));
+    }
+
+    #[test]
+    fn retains_nothing_but_the_primary_key_of_unchanged_rows() {
+        let mut before = map(json!({ "id": 1, "status": null, "before": 5 }));
+        let mut after = before.clone();
+        retain_changed_columns(&mut before, &mut after, &schema(&["id", "status"]));
+        assert_eq!(before, map(json!({ "id": 1, "status": null })));
+        assert_eq!(after, before);
+
+        let mut before = map(json!({ "id": 1, "status": "new" }));
+        let mut after = before.clone();
+        retain_changed_columns(&mut before, &mut after, &schema(&[]));
+        assert!(before.is_empty());
+        assert!(after.is_empty());
+    }
+
+    #[test]
+    fn nests_the_rows_of_flat_updates() {
+        let update = envelope(ReplicationRowEvent::Update {
+            before: row(&[Some("1"), Some("new"), Some("5")]),
+            after: row(&[Some("1"), Some("paid"), Some("5")]),
+        });
+        let (key, value) =
+            row_event_to_json(&update, &schema(&["id"]), &options(), flat()).unwrap();
+        assert_eq!(key, Some(json!({ "id": 1 })));
+        assert_eq!(
+            value,
+            json!({
+                "op": "U",
+                "before": { "id": 1, "status": "new", "before": 5 },
+                "after": { "id": 1, "status": "paid", "before": 5 },
+            })
+        );
+
+        let changed_columns_only = RowEnvelopeOptions {
+            changed_columns_only: true,
+            ..flat()
+        };
+        let (_, value) =
+            row_event_to_json(&update, &schema(&["id"]), &options(), changed_columns_only).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "op": "U",
+                "before": { "id": 1, "status": "new" },
+                "after": { "id": 1, "status": "paid" },
+            })
+        );
+
+        // Deletes carry the whole row even with only changed columns
+        let delete = envelope(ReplicationRowEvent::Delete(row(&[
+            Some("1"),
+            Some("new"),
+            Some("5"),
+        ])));
+        let (key, value) =
+            row_event_to_json(&delete, &schema(&["id"]), &options(), changed_columns_only).unwrap();
+        assert_eq!(key, Some(json!({ "id": 1 })));
+        assert_eq!(
+            value,
+            json!({ "op": "D", "id": 1, "status": "new", "before": 5 })
+        );
+    }
+
+    #[test]
+    fn splits_primary_key_updates() {
+        let split = RowEnvelopeOptions {
+            split_primary_key_updates: true,
+            ..flat()
+        };
+        let update = envelope(ReplicationRowEvent::Update {
+            before: row(&[Some("1"), Some("new"), None]),
+            after: row(&[Some("2"), Some("new"), None]),
+        });
+
+        let records = row_event_to_records(&update, &schema(&["id"]), &options(), split).unwrap();
+        let records: Vec<_> = records
+            .into_iter()
+            .map(|record| (record.key, record.value))
+            .collect();
+        assert_eq!(
+            records,
+            [
+                (
+                    Some(json!({ "id": 1 })),
+                    Some(json!({ "op": "D", "id": 1, "status": "new", "before": null }))
+                ),
+                (Some(json!({ "id": 1 })), None),
+                (
+                    Some(json!({ "id": 2 })),
+                    Some(json!({ "op": "I", "id": 2, "status": "new", "before": null }))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_updates_that_leave_the_primary_key_alone() {
+        let split = RowEnvelopeOptions {
+            split_primary_key_updates: true,
+            ..flat()
+        };
+        let update = envelope(ReplicationRowEvent::Update {
+            before: row(&[Some("1"), Some("new"), None]),
+            after: row(&[Some("1"), Some("paid"), None]),
+        });
+        let records = row_event_to_records(&update, &schema(&["id"]), &options(), split).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].value.as_ref().unwrap()["op"], json!("U"));
+
+        // Without a primary key no column identifies the row, so nothing is split
+        let update = envelope(ReplicationRowEvent::Update {
+            before: row(&[Some("1"), Some("new"), None]),
+            after: row(&[Some("2"), Some("paid"), None]),
+        });
+        let records = row_event_to_records(&update, &schema(&[]), &options(), split).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].key, None);
+        assert_eq!(records[0].value.as_ref().unwrap()["op"], json!("U"));
+
+        // Nor is it without the option
+        let update = envelope(ReplicationRowEvent::Update {
+            before: row(&[Some("1"), Some("new"), None]),
+            after: row(&[Some("2"), Some("new"), None]),
+        });
+        let records = row_event_to_records(&update, &schema(&["id"]), &options(), flat()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].key, Some(json!({ "id": 2 })));
+    }
+}
@@ -1,15 +1,24 @@
 use std::{
     error::Error,
     fmt::{self, Display, Formatter},
+    num::ParseIntError,
     str::FromStr,
     string::FromUtf8Error,
 };
 
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use encoding_rs::Encoding;
+use jiff::tz::TimeZone;
 use serde_json::Number;
 
 use crate::{
-    vitess_grpc::query::{Field, Row, Type},
-    vitess_schema::{FieldName, TableName, VitessSchema},
+    command_line_args::{
+        Args, BinaryEncoding, JsonColumnMode, NumericMode, TemporalMode, ZeroDatePolicy,
+    },
+    mysql_binary_json::{BinaryJsonError, decode_binary_json, is_binary_json_type},
+    table_row_temporal::{InvalidTemporalError, temporal_to_json},
+    vitess_grpc::query::{Field, MySqlFlag, Row, Type},
+    vitess_schema::{FieldName, TableName},
 };
 
 #[derive(Debug)]
@@ -32,7 +41,12 @@ impl Error for DeserializeRowError {
         match &self.kind {
             DeserializeRowErrorKind::StringFromBytesFailed(e) => Some(e),
             DeserializeRowErrorKind::SerdeJsonParseFailed(e) => Some(e),
+            DeserializeRowErrorKind::ParseIntFailed(e) => Some(e),
             DeserializeRowErrorKind::UnimplementedConversion(e) => Some(e),
+            DeserializeRowErrorKind::MalformedValue(e) => Some(e),
+            DeserializeRowErrorKind::UnsupportedCharset(e) => Some(e),
+            DeserializeRowErrorKind::InvalidTemporal(e) => Some(e),
+            DeserializeRowErrorKind::InvalidBinaryJson(e) => Some(e),
         }
     }
 }
@@ -41,7 +55,28 @@ impl Error for DeserializeRowError {
 pub enum DeserializeRowErrorKind {
     StringFromBytesFailed(FromUtf8Error),
     SerdeJsonParseFailed(serde_json::Error),
+    ParseIntFailed(ParseIntError),
     UnimplementedConversion(UnimplementedConversionError),
+    MalformedValue(MalformedValueError),
+    UnsupportedCharset(UnsupportedCharsetError),
+    InvalidTemporal(InvalidTemporalError),
+    InvalidBinaryJson(BinaryJsonError),
+}
+
+impl DeserializeRowErrorKind {
+    /// Stable name of the error for records that are consumed outside the replicator
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            DeserializeRowErrorKind::StringFromBytesFailed(_) => "string_from_bytes_failed",
+            DeserializeRowErrorKind::SerdeJsonParseFailed(_) => "serde_json_parse_failed",
+            DeserializeRowErrorKind::ParseIntFailed(_) => "parse_int_failed",
+            DeserializeRowErrorKind::UnimplementedConversion(_) => "unimplemented_conversion",
+            DeserializeRowErrorKind::MalformedValue(_) => "malformed_value",
+            DeserializeRowErrorKind::UnsupportedCharset(_) => "unsupported_charset",
+            DeserializeRowErrorKind::InvalidTemporal(_) => "invalid_temporal",
+            DeserializeRowErrorKind::InvalidBinaryJson(_) => "invalid_binary_json",
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -62,97 +97,928 @@ impl Display for UnimplementedConversionError {
 
 impl Error for UnimplementedConversionError {}
 
-pub(crate) fn deserialize_row_values(
-    row: Row,
-    schema: &VitessSchema,
-) -> Result<Vec<Option<String>>, DeserializeRowError> {
-    let mut pos = 0;
-    let mut row_values: Vec<Option<String>> = vec![];
-    for (col_number, (data_len, (col_name, _))) in
-        row.lengths.iter().zip(schema.schema.iter()).enumerate()
-    {
-        if *data_len < 0 {
-            // Null values have -1 for length
-            row_values.push(None);
-            continue;
-        }
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct MalformedValueError {
+    pub column_type: Type,
+    pub length: usize,
+}
 
-        let data_len_usize: usize = data_len
-            .clone()
-            .try_into()
-            .expect("Should be able to convert column data size from u64 to usize");
+impl Display for MalformedValueError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes is not a valid value for mysql type `{:?}`",
+            self.length, self.column_type
+        )
+    }
+}
 
-        let str_val = String::from_utf8(
-            row.values.as_slice()[pos..pos + data_len_usize]
-                .iter()
-                .cloned()
-                .collect(),
+impl Error for MalformedValueError {}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct UnsupportedCharsetError {
+    /// Collation id of the field
+    pub charset: u32,
+}
+
+impl Display for UnsupportedCharsetError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "text in the charset of mysql collation {} can't be decoded",
+            self.charset
         )
-        .map_err(|e| DeserializeRowError {
-            table: Box::new(schema.table.clone()),
-            column_number: col_number,
-            column_name: Box::new(col_name.clone()),
-            kind: DeserializeRowErrorKind::StringFromBytesFailed(e),
-        })?;
-        row_values.push(Some(str_val));
-        pos += data_len_usize;
     }
+}
+
+impl Error for UnsupportedCharsetError {}
 
-    Ok(row_values)
+#[derive(Debug, Clone)]
+pub(crate) struct ValueConversionOptions {
+    pub(crate) numeric_mode: NumericMode,
+    pub(crate) binary_encoding: BinaryEncoding,
+    pub(crate) json_column_mode: JsonColumnMode,
+    pub(crate) temporal_mode: TemporalMode,
+    pub(crate) source_time_zone: TimeZone,
+    pub(crate) zero_date_policy: ZeroDatePolicy,
 }
 
-fn serde_json_error(
+impl From<&Args> for ValueConversionOptions {
+    fn from(args: &Args) -> Self {
+        ValueConversionOptions {
+            numeric_mode: args.numeric_mode,
+            binary_encoding: args.binary_encoding,
+            json_column_mode: args.json_column_mode,
+            temporal_mode: args.temporal_mode,
+            source_time_zone: args.source_time_zone.clone(),
+            zero_date_policy: args.zero_date_policy,
+        }
+    }
+}
+
+pub(crate) fn raw_row_values(row: &Row) -> Vec<Option<&[u8]>> {
+    let mut pos = 0;
+    row.lengths
+        .iter()
+        .map(|data_len| {
+            // Null values have -1 for length
+            let data_len_usize = usize::try_from(*data_len).ok()?;
+            let value = &row.values[pos..pos + data_len_usize];
+            pos += data_len_usize;
+            Some(value)
+        })
+        .collect()
+}
+
+fn column_error(
     table_name: &TableName,
-    col_number: usize,
+    column_number: usize,
     field_name: &FieldName,
-    e: serde_json::Error,
+    kind: DeserializeRowErrorKind,
 ) -> DeserializeRowError {
     DeserializeRowError {
         table: Box::new(table_name.clone()),
-        column_number: col_number,
+        column_number,
         column_name: Box::new(field_name.clone()),
-        kind: DeserializeRowErrorKind::SerdeJsonParseFailed(e),
+        kind,
     }
 }
 
-pub(crate) fn transform_string_to_json_value(
-    maybe_value: Option<String>,
+/// Converts a column value as vstream encodes it, text in the column charset for most types and
+/// the mysql binary format for binary strings, `BIT`, `GEOMETRY` and `VECTOR`
+pub(crate) fn transform_value_to_json(
+    maybe_value: Option<&[u8]>,
     table_name: &TableName,
     column_number: usize,
     field_name: &FieldName,
     field: &Field,
+    options: &ValueConversionOptions,
 ) -> Result<serde_json::Value, DeserializeRowError> {
-    Ok(if let Some(value) = maybe_value {
-        match field.r#type() {
-            Type::Varchar | Type::Char => serde_json::Value::String(value),
-            Type::Varbinary | Type::Binary | Type::Blob => serde_json::Value::String(value),
-            Type::Date | Type::Datetime => serde_json::Value::String(value),
-            Type::Decimal
-            | Type::Int8
-            | Type::Int16
-            | Type::Int24
-            | Type::Int32
-            | Type::Int64
-            | Type::Float32
-            | Type::Float64 => {
-                let n = Number::from_str(&value)
-                    .map_err(|e| serde_json_error(table_name, column_number, field_name, e))?;
-                serde_json::Value::Number(n)
+    let Some(value) = maybe_value else {
+        return Ok(serde_json::Value::Null);
+    };
+    let error = |kind| column_error(table_name, column_number, field_name, kind);
+    let malformed = || {
+        error(DeserializeRowErrorKind::MalformedValue(
+            MalformedValueError {
+                column_type: field.r#type(),
+                length: value.len(),
+            },
+        ))
+    };
+    let text = || match text_encoding(field.charset) {
+        Some(encoding) if encoding == encoding_rs::UTF_8 => String::from_utf8(value.to_vec())
+            .map_err(|e| error(DeserializeRowErrorKind::StringFromBytesFailed(e))),
+        Some(encoding) => encoding
+            .decode_without_bom_handling_and_without_replacement(value)
+            .map(String::from)
+            .ok_or_else(malformed),
+        None => Err(error(DeserializeRowErrorKind::UnsupportedCharset(
+            UnsupportedCharsetError {
+                charset: field.charset,
+            },
+        ))),
+    };
+
+    Ok(match field.r#type() {
+        Type::NullType => serde_json::Value::Null,
+        Type::Varchar | Type::Char | Type::Text => serde_json::Value::String(text()?),
+        Type::Varbinary | Type::Binary | Type::Blob => {
+            serde_json::Value::String(encode_binary(value, options.binary_encoding))
+        }
+        Type::Date | Type::Datetime | Type::Timestamp | Type::Time => {
+            temporal_to_json(&text()?, field, options)
+                .map_err(|e| error(DeserializeRowErrorKind::InvalidTemporal(e)))?
+        }
+        Type::Enum => {
+            serde_json::Value::String(enum_label(&text()?, &column_labels(&field.column_type)))
+        }
+        Type::Set => serde_json::Value::Array(
+            set_labels(&text()?, &column_labels(&field.column_type))
+                .into_iter()
+                .map(serde_json::Value::String)
+                .collect(),
+        ),
+        Type::Decimal => {
+            let decimal = text()?;
+            match options.numeric_mode {
+                NumericMode::Number => serde_json::Value::Number(
+                    Number::from_str(&decimal)
+                        .map_err(|e| error(DeserializeRowErrorKind::SerdeJsonParseFailed(e)))?,
+                ),
+                NumericMode::String => serde_json::Value::String(decimal),
+                NumericMode::ScaledInteger => {
+                    let scale = field.decimals as usize;
+                    let unscaled = Number::from_str(&unscale_decimal(&decimal, scale))
+                        .map_err(|e| error(DeserializeRowErrorKind::SerdeJsonParseFailed(e)))?;
+                    serde_json::json!({
+                        "unscaled": unscaled,
+                        "scale": scale,
+                        "precision": decimal_precision(field),
+                    })
+                }
             }
-            _ => {
-                return Err(DeserializeRowError {
-                    table: Box::new(table_name.clone()),
-                    column_number,
-                    column_name: Box::new(field_name.clone()),
-                    kind: DeserializeRowErrorKind::UnimplementedConversion(
-                        UnimplementedConversionError {
-                            column_type: field.r#type(),
-                        },
-                    ),
-                });
+        }
+        Type::Int64 | Type::Uint64 if options.numeric_mode == NumericMode::String => {
+            serde_json::Value::String(text()?)
+        }
+        // Consumers of scaled integers read int64, which the upper half of the range exceeds
+        Type::Uint64 if options.numeric_mode == NumericMode::ScaledInteger => {
+            serde_json::Value::String(text()?)
+        }
+        // Parsed rather than read as JSON numbers, ZEROFILL columns and YEAR 0000 come with
+        // leading zeros
+        Type::Int8 | Type::Int16 | Type::Int24 | Type::Int32 | Type::Int64 => {
+            let n = i64::from_str(&text()?)
+                .map_err(|e| error(DeserializeRowErrorKind::ParseIntFailed(e)))?;
+            serde_json::Value::Number(n.into())
+        }
+        Type::Uint8 | Type::Uint16 | Type::Uint24 | Type::Uint32 | Type::Uint64 | Type::Year => {
+            let n = u64::from_str(&text()?)
+                .map_err(|e| error(DeserializeRowErrorKind::ParseIntFailed(e)))?;
+            serde_json::Value::Number(n.into())
+        }
+        Type::Float32 | Type::Float64 => {
+            let n = Number::from_str(trim_zero_fill(&text()?))
+                .map_err(|e| error(DeserializeRowErrorKind::SerdeJsonParseFailed(e)))?;
+            serde_json::Value::Number(n)
+        }
+        Type::Json => {
+            let document = json_column_to_json(value).map_err(error)?;
+            match options.json_column_mode {
+                JsonColumnMode::Nested => document,
+                JsonColumnMode::String => serde_json::Value::String(document.to_string()),
+            }
+        }
+        // Big endian and at most 64 bits
+        Type::Bit => {
+            if value.len() > 8 {
+                return Err(malformed());
             }
+            let bits = value
+                .iter()
+                .fold(0u64, |bits, byte| (bits << 8) | u64::from(*byte));
+            serde_json::Value::Number(bits.into())
+        }
+        // Little endian SRID followed by the WKB of the geometry
+        Type::Geometry => {
+            let Some((srid, wkb)) = value.split_first_chunk::<4>() else {
+                return Err(malformed());
+            };
+            serde_json::json!({
+                "srid": u32::from_le_bytes(*srid),
+                "wkb": encode_binary(wkb, options.binary_encoding),
+            })
+        }
+        // Little endian 32 bit floats
+        Type::Vector => {
+            if value.len() % 4 != 0 {
+                return Err(malformed());
+            }
+            serde_json::Value::Array(
+                value
+                    .chunks_exact(4)
+                    .map(|chunk| {
+                        let element =
+                            f32::from_le_bytes(chunk.try_into().expect("Chunk of 4 bytes"));
+                        Number::from_f64(f64::from(element))
+                            .map(serde_json::Value::Number)
+                            .unwrap_or(serde_json::Value::Null)
+                    })
+                    .collect(),
+            )
+        }
+        // Only used for bind variables and expressions, never for column values
+        Type::Tuple
+        | Type::Expression
+        | Type::HexNum
+        | Type::HexVal
+        | Type::BitNum
+        | Type::Raw
+        | Type::RowTuple => {
+            return Err(error(DeserializeRowErrorKind::UnimplementedConversion(
+                UnimplementedConversionError {
+                    column_type: field.r#type(),
+                },
+            )));
         }
-    } else {
-        serde_json::Value::Null
     })
 }
+
+// Mysql formats decimals with exactly `scale` fraction digits, pad or cut anyway so a value that
+// doesn't match its column still scales correctly
+fn unscale_decimal(decimal: &str, scale: usize) -> String {
+    let (sign, digits) = match decimal.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", decimal),
+    };
+    let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    let fraction = format!("{:0<scale$}", &fraction[..fraction.len().min(scale)]);
+
+    let unscaled = format!("{}{}", integer, fraction);
+    match unscaled.trim_start_matches('0') {
+        "" => "0".to_string(),
+        unscaled => format!("{}{}", sign, unscaled),
+    }
+}
+
+// The column length of a decimal counts the sign and the decimal point next to the digits
+fn decimal_precision(field: &Field) -> u32 {
+    let sign = u32::from(field.flags & MySqlFlag::UnsignedFlag as u32 == 0);
+    let point = u32::from(field.decimals > 0);
+    field.column_length.saturating_sub(sign + point)
+}
+
+// Vstream sends json columns as text, but depending on the vitess version and the binlog
+// settings the binary format mysql stores them in can come through as well. Text is tried first,
+// so the rare binary `INT64` or `UINT64` whose bytes are also json text reads as text.
+fn json_column_to_json(value: &[u8]) -> Result<serde_json::Value, DeserializeRowErrorKind> {
+    let text_error = match serde_json::from_slice(value) {
+        Ok(document) => return Ok(document),
+        Err(e) => e,
+    };
+    match value.first() {
+        None => decode_binary_json(value).map_err(DeserializeRowErrorKind::InvalidBinaryJson),
+        Some(&first) if is_binary_json_type(first) => {
+            decode_binary_json(value).map_err(DeserializeRowErrorKind::InvalidBinaryJson)
+        }
+        Some(_) => Err(DeserializeRowErrorKind::SerdeJsonParseFailed(text_error)),
+    }
+}
+
+// Labels of an `enum('a','b')` or `set('a','b')` column type, quotes inside a label are doubled
+fn column_labels(column_type: &str) -> Vec<String> {
+    let Some(labels) = column_type
+        .split_once('(')
+        .and_then(|(_, labels)| labels.strip_suffix(')'))
+    else {
+        return vec![];
+    };
+
+    let mut parsed = vec![];
+    let mut chars = labels.chars().peekable();
+    while chars.next_if_eq(&'\'').is_some() {
+        let mut label = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '\'' if chars.next_if_eq(&'\'').is_some() => label.push('\''),
+                '\'' => break,
+                '\\' => label.extend(chars.next()),
+                c => label.push(c),
+            }
+        }
+        parsed.push(label);
+        chars.next_if_eq(&',');
+    }
+    parsed
+}
+
+// Depending on the vitess version vstream sends the label or the 1 based ordinal of the label,
+// a label that looks like a number wins over the ordinal. Ordinal 0 is the empty string mysql
+// stores for invalid values.
+fn enum_label(value: &str, labels: &[String]) -> String {
+    if labels.iter().any(|label| label == value) {
+        return value.to_string();
+    }
+    match value.parse::<usize>() {
+        Ok(0) => String::new(),
+        Ok(ordinal) => labels
+            .get(ordinal - 1)
+            .cloned()
+            .unwrap_or_else(|| value.to_string()),
+        Err(_) => value.to_string(),
+    }
+}
+
+// Sets arrive as the comma separated labels in definition order, or as a bitmask with a bit per
+// label. Labels can't contain commas.
+fn set_labels(value: &str, labels: &[String]) -> Vec<String> {
+    if value.is_empty() {
+        return vec![];
+    }
+    let members: Vec<&str> = value.split(',').collect();
+    if !members
+        .iter()
+        .all(|member| labels.iter().any(|label| label == member))
+        && let Ok(bitmask) = value.parse::<u64>()
+    {
+        return labels
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| *bit < 64 && bitmask & (1 << bit) != 0)
+            .map(|(_, label)| label.clone())
+            .collect();
+    }
+    members.into_iter().map(str::to_string).collect()
+}
+
+fn encode_binary(value: &[u8], encoding: BinaryEncoding) -> String {
+    match encoding {
+        BinaryEncoding::Base64 => BASE64.encode(value),
+        BinaryEncoding::Hex => value.iter().map(|byte| format!("{:02x}", byte)).collect(),
+    }
+}
+
+// Text arrives in the charset of its field, which is identified by the id of its collation.
+// Drops the padding of an unsigned ZEROFILL float, keeping a single zero before the decimal point
+fn trim_zero_fill(number: &str) -> &str {
+    let digits = number.trim_start_matches('0');
+    match digits.chars().next() {
+        _ if digits.len() == number.len() => number,
+        Some(c) if c.is_ascii_digit() => digits,
+        _ => &number[number.len() - digits.len() - 1..],
+    }
+}
+
+// Numbers and temporal values come with the binary collation and are ascii, vttablets that don't
+// report a collation send 0. Mysql's latin1 is windows-1252 rather than iso-8859-1.
+fn text_encoding(collation: u32) -> Option<&'static Encoding> {
+    Some(match collation {
+        0 | 11 | 33 | 45 | 46 | 63 | 65 | 76 | 83 | 192..=215 | 223..=247 | 255..=323 => {
+            encoding_rs::UTF_8
+        }
+        5 | 8 | 15 | 31 | 47 | 48 | 49 | 94 => encoding_rs::WINDOWS_1252,
+        26 | 34 | 44 | 66 | 99 => encoding_rs::WINDOWS_1250,
+        14 | 23 | 50 | 51 | 52 => encoding_rs::WINDOWS_1251,
+        57 | 67 => encoding_rs::WINDOWS_1256,
+        29 | 58 | 59 => encoding_rs::WINDOWS_1257,
+        2 | 9 | 21 | 27 | 77 => encoding_rs::ISO_8859_2,
+        25 | 70 => encoding_rs::ISO_8859_7,
+        16 | 71 => encoding_rs::ISO_8859_8,
+        20 | 41 | 42 | 79 => encoding_rs::ISO_8859_13,
+        7 | 74 => encoding_rs::KOI8_R,
+        22 | 75 => encoding_rs::KOI8_U,
+        36 | 68 => encoding_rs::IBM866,
+        39 | 53 => encoding_rs::MACINTOSH,
+        30 | 78 => encoding_rs::WINDOWS_1254,
+        // Shift_JIS, GBK and windows-874 are the cp932, gb2312 and tis620 supersets
+        13 | 88 | 95 | 96 => encoding_rs::SHIFT_JIS,
+        18 | 89 => encoding_rs::WINDOWS_874,
+        12 | 91 | 97 | 98 => encoding_rs::EUC_JP,
+        19 | 85 => encoding_rs::EUC_KR,
+        1 | 84 => encoding_rs::BIG5,
+        24 | 28 | 86 | 87 => encoding_rs::GBK,
+        248..=250 => encoding_rs::GB18030,
+        54 | 55 | 101..=124 => encoding_rs::UTF_16BE,
+        56 | 62 => encoding_rs::UTF_16LE,
+        // ucs2, utf32 and the dos and hp charsets encoding_rs has no decoder for
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::vitess_schema::VitessSchema;
+
+    const UTF8MB4_COLLATION: u32 = 255;
+    const BINARY_COLLATION: u32 = 63;
+
+    fn options() -> ValueConversionOptions {
+        ValueConversionOptions {
+            numeric_mode: NumericMode::Number,
+            binary_encoding: BinaryEncoding::Base64,
+            json_column_mode: JsonColumnMode::Nested,
+            temporal_mode: TemporalMode::Iso8601,
+            source_time_zone: TimeZone::UTC,
+            zero_date_policy: ZeroDatePolicy::Null,
+        }
+    }
+
+    fn field(column_type: Type) -> Field {
+        Field {
+            name: "value".to_string(),
+            r#type: column_type.into(),
+            charset: UTF8MB4_COLLATION,
+            ..Default::default()
+        }
+    }
+
+    fn convert_with(
+        value: Option<&[u8]>,
+        field: &Field,
+        options: &ValueConversionOptions,
+    ) -> Result<serde_json::Value, DeserializeRowError> {
+        let table: TableName = "items".to_string().into();
+        let schema =
+            VitessSchema::from_field_event(table.clone(), std::slice::from_ref(field), None);
+        let (field_name, field) = &schema.schema[0];
+        transform_value_to_json(value, &table, 0, field_name, field, options)
+    }
+
+    fn convert(value: &[u8], column_type: Type) -> serde_json::Value {
+        convert_with(Some(value), &field(column_type), &options()).unwrap()
+    }
+
+    fn convert_error(value: &[u8], column_type: Type) -> DeserializeRowErrorKind {
+        convert_with(Some(value), &field(column_type), &options())
+            .unwrap_err()
+            .kind
+    }
+
+    #[test]
+    fn converts_null() {
+        assert_eq!(convert(b"", Type::NullType), serde_json::Value::Null);
+        assert_eq!(
+            convert_with(None, &field(Type::Int32), &options()).unwrap(),
+            serde_json::Value::Null
+        );
+    }
+
+    #[test]
+    fn converts_text_and_binary() {
+        assert_eq!(convert("héllo".as_bytes(), Type::Varchar), json!("héllo"));
+        assert_eq!(convert(b"abc", Type::Char), json!("abc"));
+        assert_eq!(convert(b"abc", Type::Text), json!("abc"));
+
+        let binary = Field {
+            charset: BINARY_COLLATION,
+            ..field(Type::Varbinary)
+        };
+        assert_eq!(
+            convert_with(Some(&[0x00, 0xff]), &binary, &options()).unwrap(),
+            json!("AP8=")
+        );
+        assert_eq!(convert(&[0xde, 0xad], Type::Blob), json!("3q0="));
+        assert_eq!(convert(&[0x01], Type::Binary), json!("AQ=="));
+        assert!(matches!(
+            convert_error(&[0xff], Type::Varchar),
+            DeserializeRowErrorKind::StringFromBytesFailed(_)
+        ));
+    }
+
+    #[test]
+    fn decodes_text_in_the_field_charset() {
+        let text = |value: &[u8], charset| {
+            let field = Field {
+                charset,
+                ..field(Type::Varchar)
+            };
+            convert_with(Some(value), &field, &options())
+        };
+        // latin1_swedish_ci keeps the bytes windows-1252 leaves undefined as control characters
+        assert_eq!(
+            text(&[0x80, 0x81, 0xe9], 8).unwrap(),
+            json!("\u{20ac}\u{0081}\u{e9}")
+        );
+        // cp1251_general_ci
+        assert_eq!(text(&[0xcf, 0xf0, 0xe8], 51).unwrap(), json!("При"));
+        // sjis_japanese_ci
+        assert_eq!(text(&[0x93, 0xfa, 0x96, 0x7b], 13).unwrap(), json!("日本"));
+        // gbk_chinese_ci
+        assert_eq!(text(&[0xd6, 0xd0], 28).unwrap(), json!("中"));
+        // utf16_general_ci is big endian
+        assert_eq!(text(&[0x00, 0x41, 0x00, 0xe9], 54).unwrap(), json!("Aé"));
+        // Numbers come with the binary collation
+        let number = Field {
+            charset: BINARY_COLLATION,
+            ..field(Type::Int32)
+        };
+        assert_eq!(
+            convert_with(Some(b"42"), &number, &options()).unwrap(),
+            json!(42)
+        );
+
+        assert!(matches!(
+            text(&[0xc3, 0x28], UTF8MB4_COLLATION).unwrap_err().kind,
+            DeserializeRowErrorKind::StringFromBytesFailed(_)
+        ));
+        // A lead byte of sjis without its trail byte
+        assert!(matches!(
+            text(&[0x93], 13).unwrap_err().kind,
+            DeserializeRowErrorKind::MalformedValue(MalformedValueError { length: 1, .. })
+        ));
+        // ucs2_general_ci
+        match text(b"abc", 35).unwrap_err().kind {
+            DeserializeRowErrorKind::UnsupportedCharset(e) => assert_eq!(e.charset, 35),
+            kind => panic!("decoded ucs2 with {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn encodes_binary() {
+        let value = [0x00, 0x0f, 0xab, 0xff];
+        assert_eq!(encode_binary(&value, BinaryEncoding::Hex), "000fabff");
+        assert_eq!(encode_binary(&value, BinaryEncoding::Base64), "AA+r/w==");
+        assert_eq!(encode_binary(&[], BinaryEncoding::Hex), "");
+        assert_eq!(encode_binary(&[], BinaryEncoding::Base64), "");
+
+        let hex = ValueConversionOptions {
+            binary_encoding: BinaryEncoding::Hex,
+            ..options()
+        };
+        assert_eq!(
+            convert_with(Some(&value), &field(Type::Varbinary), &hex).unwrap(),
+            json!("000fabff")
+        );
+    }
+
+    #[test]
+    fn converts_integers() {
+        assert_eq!(convert(b"-128", Type::Int8), json!(-128));
+        assert_eq!(convert(b"255", Type::Uint8), json!(255));
+        assert_eq!(convert(b"-32768", Type::Int16), json!(-32768));
+        assert_eq!(convert(b"65535", Type::Uint16), json!(65535));
+        assert_eq!(convert(b"-8388608", Type::Int24), json!(-8388608));
+        assert_eq!(convert(b"16777215", Type::Uint24), json!(16777215));
+        assert_eq!(convert(b"-2147483648", Type::Int32), json!(-2147483648i64));
+        assert_eq!(convert(b"4294967295", Type::Uint32), json!(4294967295u64));
+        assert_eq!(
+            convert(b"-9223372036854775808", Type::Int64),
+            json!(i64::MIN)
+        );
+        assert_eq!(
+            convert(b"18446744073709551615", Type::Uint64),
+            json!(u64::MAX)
+        );
+        assert_eq!(convert(b"2024", Type::Year), json!(2024));
+        assert!(matches!(
+            convert_error(b"12a", Type::Int32),
+            DeserializeRowErrorKind::ParseIntFailed(_)
+        ));
+
+        let string_mode = ValueConversionOptions {
+            numeric_mode: NumericMode::String,
+            ..options()
+        };
+        assert_eq!(
+            convert_with(
+                Some(b"18446744073709551615"),
+                &field(Type::Uint64),
+                &string_mode
+            )
+            .unwrap(),
+            json!("18446744073709551615")
+        );
+        assert_eq!(
+            convert_with(Some(b"-1"), &field(Type::Int64), &string_mode).unwrap(),
+            json!("-1")
+        );
+        // Only 64 bit integers can exceed what a double holds exactly
+        assert_eq!(
+            convert_with(Some(b"-1"), &field(Type::Int32), &string_mode).unwrap(),
+            json!(-1)
+        );
+    }
+
+    #[test]
+    fn converts_unsigned_bigints_to_strings_as_scaled_integers() {
+        let scaled = ValueConversionOptions {
+            numeric_mode: NumericMode::ScaledInteger,
+            ..options()
+        };
+        assert_eq!(
+            convert_with(Some(b"18446744073709551615"), &field(Type::Uint64), &scaled).unwrap(),
+            json!("18446744073709551615")
+        );
+        assert_eq!(
+            convert_with(Some(b"-1"), &field(Type::Int64), &scaled).unwrap(),
+            json!(-1)
+        );
+    }
+
+    #[test]
+    fn unscales_decimals() {
+        assert_eq!(unscale_decimal("123.45", 2), "12345");
+        assert_eq!(unscale_decimal("-123.45", 2), "-12345");
+        assert_eq!(unscale_decimal("0.05", 2), "5");
+        assert_eq!(unscale_decimal("-0.05", 2), "-5");
+        assert_eq!(unscale_decimal("0.00", 2), "0");
+        assert_eq!(unscale_decimal("-0.00", 2), "0");
+        assert_eq!(unscale_decimal("42", 0), "42");
+        // Pads missing fraction digits and cuts extra ones
+        assert_eq!(unscale_decimal("1.5", 3), "1500");
+        assert_eq!(unscale_decimal("7", 2), "700");
+        assert_eq!(unscale_decimal("1.2345", 2), "123");
+        assert_eq!(unscale_decimal("-1.2345", 0), "-1");
+    }
+
+    #[test]
+    fn computes_decimal_precision() {
+        let decimal = |column_length, decimals, flags| Field {
+            column_length,
+            decimals,
+            flags,
+            ..field(Type::Decimal)
+        };
+        // DECIMAL(10,2) has a sign and a decimal point
+        assert_eq!(decimal_precision(&decimal(12, 2, 0)), 10);
+        // DECIMAL(10,0) has no decimal point
+        assert_eq!(decimal_precision(&decimal(11, 0, 0)), 10);
+        // DECIMAL(10,2) UNSIGNED has no sign
+        assert_eq!(
+            decimal_precision(&decimal(11, 2, MySqlFlag::UnsignedFlag as u32)),
+            10
+        );
+        assert_eq!(
+            decimal_precision(&decimal(10, 0, MySqlFlag::UnsignedFlag as u32)),
+            10
+        );
+        assert_eq!(decimal_precision(&decimal(0, 2, 0)), 0);
+    }
+
+    #[test]
+    fn converts_scaled_decimals() {
+        let scaled = ValueConversionOptions {
+            numeric_mode: NumericMode::ScaledInteger,
+            ..options()
+        };
+        let decimal = Field {
+            column_length: 12,
+            decimals: 2,
+            ..field(Type::Decimal)
+        };
+        assert_eq!(
+            convert_with(Some(b"-0.50"), &decimal, &scaled).unwrap(),
+            json!({ "unscaled": -50, "scale": 2, "precision": 10 })
+        );
+    }
+
+    #[test]
+    fn converts_zero_filled_numbers() {
+        assert_eq!(convert(b"0000", Type::Year), json!(0));
+
+        let zero_fill = Field {
+            column_type: "int(5) unsigned zerofill".to_string(),
+            ..field(Type::Uint32)
+        };
+        assert_eq!(
+            convert_with(Some(b"00042"), &zero_fill, &options()).unwrap(),
+            json!(42)
+        );
+        assert_eq!(convert(b"0001.5", Type::Float32), json!(1.5));
+        assert_eq!(convert(b"000.25", Type::Float64), json!(0.25));
+    }
+
+    #[test]
+    fn converts_floats_and_decimals() {
+        assert_eq!(convert(b"1.5", Type::Float32), json!(1.5));
+        assert_eq!(convert(b"-2.25", Type::Float64), json!(-2.25));
+        assert_eq!(convert(b"12.50", Type::Decimal).to_string(), "12.50");
+    }
+
+    #[test]
+    fn converts_temporal_enum_set_and_json() {
+        assert_eq!(convert(b"2024-01-02", Type::Date), json!("2024-01-02"));
+        assert_eq!(convert(b"03:04:05", Type::Time), json!("PT3H4M5S"));
+
+        let enum_field = Field {
+            column_type: "enum('small','large')".to_string(),
+            ..field(Type::Enum)
+        };
+        assert_eq!(
+            convert_with(Some(b"large"), &enum_field, &options()).unwrap(),
+            json!("large")
+        );
+        let set_field = Field {
+            column_type: "set('a','b')".to_string(),
+            ..field(Type::Set)
+        };
+        assert_eq!(
+            convert_with(Some(b"a,b"), &set_field, &options()).unwrap(),
+            json!(["a", "b"])
+        );
+
+        assert_eq!(convert(br#"{"a":[1]}"#, Type::Json), json!({ "a": [1] }));
+    }
+
+    #[test]
+    fn converts_json_text_and_binary_documents() {
+        assert_eq!(
+            json_column_to_json(br#"{"a": [1, "x"]}"#).unwrap(),
+            json!({ "a": [1, "x"] })
+        );
+        assert_eq!(json_column_to_json(b"\t[1]\n").unwrap(), json!([1]));
+        // UINT16 65535
+        assert_eq!(
+            json_column_to_json(&[0x06, 0xff, 0xff]).unwrap(),
+            json!(65535)
+        );
+        assert!(matches!(
+            json_column_to_json(&[0x00, 2, 0]).unwrap_err(),
+            DeserializeRowErrorKind::InvalidBinaryJson(_)
+        ));
+        assert!(matches!(
+            json_column_to_json(b"{\"a\":").unwrap_err(),
+            DeserializeRowErrorKind::SerdeJsonParseFailed(_)
+        ));
+    }
+
+    #[test]
+    fn prefers_json_text_for_ambiguous_documents() {
+        // Binary INT64 and UINT64 start with a tab and a newline, with eight ascii digits as
+        // their value they are valid json text as well, which vstream sends far more often
+        let int64 = [&[0x09][..], b"12345678"].concat();
+        assert_eq!(json_column_to_json(&int64).unwrap(), json!(12345678));
+        let uint64 = [&[0x0a][..], b"87654321"].concat();
+        assert_eq!(json_column_to_json(&uint64).unwrap(), json!(87654321));
+
+        // Other values of those types aren't json text
+        let mut int64 = vec![0x09];
+        int64.extend((-2i64).to_le_bytes());
+        assert_eq!(json_column_to_json(&int64).unwrap(), json!(-2));
+    }
+
+    #[test]
+    fn tells_json_null_from_sql_null() {
+        let string_mode = ValueConversionOptions {
+            json_column_mode: JsonColumnMode::String,
+            ..options()
+        };
+        let json_field = field(Type::Json);
+
+        assert_eq!(convert(b"null", Type::Json), serde_json::Value::Null);
+        assert_eq!(
+            convert_with(Some(b"null"), &json_field, &string_mode).unwrap(),
+            json!("null")
+        );
+        // An empty binary document is the null literal
+        assert_eq!(
+            convert_with(Some(b""), &json_field, &string_mode).unwrap(),
+            json!("null")
+        );
+        assert_eq!(
+            convert_with(None, &json_field, &string_mode).unwrap(),
+            serde_json::Value::Null
+        );
+        assert_eq!(
+            convert_with(Some(br#"{"a":1}"#), &json_field, &string_mode).unwrap(),
+            json!(r#"{"a":1}"#)
+        );
+    }
+
+    fn labels(labels: &[&str]) -> Vec<String> {
+        labels.iter().map(|label| label.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_column_labels() {
+        assert_eq!(column_labels("enum('a','b')"), labels(&["a", "b"]));
+        assert_eq!(
+            column_labels(r"enum('it''s','a\\b','\'q','c,d','')"),
+            labels(&["it's", r"a\b", "'q", "c,d", ""])
+        );
+        assert_eq!(column_labels("set('x')"), labels(&["x"]));
+        assert!(column_labels("varchar").is_empty());
+    }
+
+    #[test]
+    fn converts_enum_labels() {
+        let sizes = labels(&["small", "large"]);
+        assert_eq!(enum_label("large", &sizes), "large");
+        assert_eq!(enum_label("1", &sizes), "small");
+        assert_eq!(enum_label("2", &sizes), "large");
+        // Mysql stores invalid values as ordinal 0
+        assert_eq!(enum_label("0", &sizes), "");
+        assert_eq!(enum_label("", &sizes), "");
+        // Neither a label nor an ordinal of the column, written as is
+        assert_eq!(enum_label("3", &sizes), "3");
+        assert_eq!(enum_label("medium", &sizes), "medium");
+
+        // Labels that are numbers win over the ordinal
+        let numbers = labels(&["10", "2", "30"]);
+        assert_eq!(enum_label("2", &numbers), "2");
+        assert_eq!(enum_label("3", &numbers), "30");
+        assert_eq!(enum_label("1", &numbers), "10");
+    }
+
+    #[test]
+    fn converts_set_labels() {
+        let flags = labels(&["a", "b", "c"]);
+        assert!(set_labels("", &flags).is_empty());
+        assert_eq!(set_labels("a,c", &flags), labels(&["a", "c"]));
+        // Bitmask with the bits of the first and the third label
+        assert_eq!(set_labels("5", &flags), labels(&["a", "c"]));
+        assert_eq!(set_labels("0", &flags), labels(&[]));
+        // Bits past the last label are dropped
+        assert_eq!(set_labels("10", &flags), labels(&["b"]));
+
+        // Labels that are numbers win over the bitmask
+        let numbers = labels(&["1", "2", "4"]);
+        assert_eq!(set_labels("1,4", &numbers), labels(&["1", "4"]));
+        assert_eq!(set_labels("4", &numbers), labels(&["4"]));
+        assert_eq!(set_labels("3", &numbers), labels(&["1", "2"]));
+
+        let quoted = Field {
+            column_type: r"set('it''s','a\\b')".to_string(),
+            ..field(Type::Set)
+        };
+        assert_eq!(
+            convert_with(Some(br"it's,a\b"), &quoted, &options()).unwrap(),
+            json!(["it's", r"a\b"])
+        );
+        assert_eq!(
+            convert_with(Some(b""), &quoted, &options()).unwrap(),
+            json!([])
+        );
+    }
+
+    #[test]
+    fn converts_bits() {
+        assert_eq!(convert(&[0x05], Type::Bit), json!(5));
+        assert_eq!(convert(&[0x01, 0x00], Type::Bit), json!(256));
+        assert_eq!(convert(&[0xff; 8], Type::Bit), json!(u64::MAX));
+        assert!(matches!(
+            convert_error(&[0x01; 9], Type::Bit),
+            DeserializeRowErrorKind::MalformedValue(MalformedValueError {
+                column_type: Type::Bit,
+                length: 9,
+            })
+        ));
+    }
+
+    #[test]
+    fn converts_geometries() {
+        let mut value = 4326u32.to_le_bytes().to_vec();
+        value.extend([0x01, 0x02]);
+        assert_eq!(
+            convert(&value, Type::Geometry),
+            json!({ "srid": 4326, "wkb": "AQI=" })
+        );
+        assert!(matches!(
+            convert_error(&[0x00; 3], Type::Geometry),
+            DeserializeRowErrorKind::MalformedValue(MalformedValueError { length: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn converts_vectors() {
+        let value: Vec<u8> = [1.5f32, -2.0]
+            .iter()
+            .flat_map(|element| element.to_le_bytes())
+            .collect();
+        assert_eq!(convert(&value, Type::Vector), json!([1.5, -2.0]));
+        assert_eq!(convert(&[], Type::Vector), json!([]));
+        assert!(matches!(
+            convert_error(&value[..5], Type::Vector),
+            DeserializeRowErrorKind::MalformedValue(MalformedValueError {
+                column_type: Type::Vector,
+                length: 5,
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_types_that_are_never_column_values() {
+        for column_type in [
+            Type::Tuple,
+            Type::Expression,
+            Type::HexNum,
+            Type::HexVal,
+            Type::BitNum,
+            Type::Raw,
+            Type::RowTuple,
+        ] {
+            match convert_error(b"1", column_type) {
+                DeserializeRowErrorKind::UnimplementedConversion(e) => {
+                    assert_eq!(e.column_type, column_type)
+                }
+                kind => panic!("{:?} converted with {:?}", column_type, kind),
+            }
+        }
+    }
+}
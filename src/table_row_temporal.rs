@@ -0,0 +1,415 @@
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+};
+
+use jiff::{
+    Timestamp,
+    civil::{Date, DateTime},
+    tz::TimeZone,
+};
+
+use crate::{
+    command_line_args::{TemporalMode, ZeroDatePolicy},
+    table_row_deserializer::ValueConversionOptions,
+    vitess_grpc::query::{Field, Type},
+};
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct InvalidTemporalError {
+    pub column_type: Type,
+    pub value: String,
+    pub zero_date: bool,
+}
+
+impl Display for InvalidTemporalError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.zero_date {
+            write!(
+                f,
+                "zero date `{}` in mysql `{:?}` column",
+                self.value, self.column_type
+            )
+        } else {
+            write!(
+                f,
+                "`{}` is not a valid mysql `{:?}`",
+                self.value, self.column_type
+            )
+        }
+    }
+}
+
+impl Error for InvalidTemporalError {}
+
+// Mysql supports at most microseconds, fields of expressions report 31 for "not fixed"
+const MAX_FRACTIONAL_DIGITS: usize = 6;
+
+/// Converts the text vstream sends for `DATE`, `DATETIME`, `TIMESTAMP` and `TIME` columns.
+/// `DATETIME` is local to the configured source time zone while vstream formats `TIMESTAMP` in
+/// UTC, both are written as UTC instants.
+pub(crate) fn temporal_to_json(
+    value: &str,
+    field: &Field,
+    options: &ValueConversionOptions,
+) -> Result<serde_json::Value, InvalidTemporalError> {
+    let column_type = field.r#type();
+    let invalid = |zero_date| InvalidTemporalError {
+        column_type,
+        value: value.to_string(),
+        zero_date,
+    };
+    let fractional_digits = (field.decimals as usize).min(MAX_FRACTIONAL_DIGITS);
+
+    if column_type == Type::Time {
+        let micros = parse_time(value).ok_or_else(|| invalid(false))?;
+        return Ok(match options.temporal_mode {
+            TemporalMode::Iso8601 => {
+                serde_json::Value::String(format_duration(micros, fractional_digits))
+            }
+            TemporalMode::EpochMillis => serde_json::Value::from(micros / 1000),
+            TemporalMode::EpochMicros => serde_json::Value::from(micros),
+        });
+    }
+
+    let (date, time) = value.split_once(' ').unwrap_or((value, ""));
+    let (year, month, day) = parse_date(date).ok_or_else(|| invalid(false))?;
+    // Mysql accepts zero for the whole date or any of its parts unless NO_ZERO_DATE is set
+    if year == 0 || month == 0 || day == 0 {
+        return match options.zero_date_policy {
+            ZeroDatePolicy::Null => Ok(serde_json::Value::Null),
+            ZeroDatePolicy::Raw => Ok(serde_json::Value::String(value.to_string())),
+            ZeroDatePolicy::Fail => Err(invalid(true)),
+        };
+    }
+    let date = Date::new(year, month, day).map_err(|_| invalid(false))?;
+
+    if column_type == Type::Date {
+        return Ok(match options.temporal_mode {
+            TemporalMode::Iso8601 => serde_json::Value::String(date.to_string()),
+            mode => {
+                let midnight = date
+                    .to_zoned(TimeZone::UTC)
+                    .map_err(|_| invalid(false))?
+                    .timestamp();
+                epoch_to_json(midnight, mode)
+            }
+        });
+    }
+
+    let (hour, minute, second, micros) = parse_time_of_day(time).ok_or_else(|| invalid(false))?;
+    let datetime = DateTime::new(
+        date.year(),
+        date.month(),
+        date.day(),
+        hour,
+        minute,
+        second,
+        micros * 1000,
+    )
+    .map_err(|_| invalid(false))?;
+    let time_zone = match column_type {
+        Type::Timestamp => TimeZone::UTC,
+        _ => options.source_time_zone.clone(),
+    };
+    let timestamp = datetime
+        .to_zoned(time_zone)
+        .map_err(|_| invalid(false))?
+        .timestamp();
+
+    Ok(match options.temporal_mode {
+        TemporalMode::Iso8601 => {
+            serde_json::Value::String(format_timestamp(timestamp, fractional_digits))
+        }
+        mode => epoch_to_json(timestamp, mode),
+    })
+}
+
+fn epoch_to_json(timestamp: Timestamp, mode: TemporalMode) -> serde_json::Value {
+    match mode {
+        TemporalMode::EpochMicros => serde_json::Value::from(timestamp.as_microsecond()),
+        _ => serde_json::Value::from(timestamp.as_millisecond()),
+    }
+}
+
+// `YYYY-MM-DD`
+fn parse_date(date: &str) -> Option<(i16, i8, i8)> {
+    let mut parts = date.splitn(3, '-');
+    let year = parts.next()?.parse().ok()?;
+    let month = parts.next()?.parse().ok()?;
+    let day = parts.next()?.parse().ok()?;
+    Some((year, month, day))
+}
+
+// `HH:MM:SS[.ffffff]`
+fn parse_time_of_day(time: &str) -> Option<(i8, i8, i8, i32)> {
+    let (hour, minute, second, micros) = parse_clock(time)?;
+    Some((
+        i8::try_from(hour).ok()?,
+        minute,
+        second,
+        i32::try_from(micros).ok()?,
+    ))
+}
+
+// `[-]HHH:MM:SS[.ffffff]` as signed microseconds, a `TIME` is a duration of up to 838 hours
+fn parse_time(time: &str) -> Option<i64> {
+    let (sign, time) = match time.strip_prefix('-') {
+        Some(time) => (-1, time),
+        None => (1, time),
+    };
+    let (hours, minutes, seconds, micros) = parse_clock(time)?;
+    Some(
+        sign * (((hours * 60 + i64::from(minutes)) * 60 + i64::from(seconds)) * 1_000_000 + micros),
+    )
+}
+
+fn parse_clock(clock: &str) -> Option<(i64, i8, i8, i64)> {
+    let (clock, fraction) = clock.split_once('.').unwrap_or((clock, ""));
+    let mut parts = clock.splitn(3, ':');
+    let hours = parts.next()?.parse().ok()?;
+    let minutes = parts.next()?.parse().ok()?;
+    let seconds = parts.next()?.parse().ok()?;
+
+    if fraction.len() > MAX_FRACTIONAL_DIGITS || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let micros = format!("{:0<width$}", fraction, width = MAX_FRACTIONAL_DIGITS)
+        .parse()
+        .ok()?;
+    Some((hours, minutes, seconds, micros))
+}
+
+// `YYYY-MM-DDTHH:MM:SS[.f]Z` with as many fractional digits as the column declares
+fn format_timestamp(timestamp: Timestamp, fractional_digits: usize) -> String {
+    let utc = timestamp.to_zoned(TimeZone::UTC).datetime();
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}Z",
+        utc.year(),
+        utc.month(),
+        utc.day(),
+        utc.hour(),
+        utc.minute(),
+        utc.second(),
+        format_fraction(i64::from(utc.subsec_nanosecond() / 1000), fractional_digits)
+    )
+}
+
+// ISO-8601 duration like `-PT838H59M59.000000S`, hours aren't folded into days so the value
+// reads like the mysql one
+fn format_duration(micros: i64, fractional_digits: usize) -> String {
+    let sign = if micros < 0 { "-" } else { "" };
+    let micros = micros.abs();
+    let seconds = micros / 1_000_000;
+    format!(
+        "{}PT{}H{}M{}{}S",
+        sign,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        format_fraction(micros % 1_000_000, fractional_digits)
+    )
+}
+
+fn format_fraction(micros: i64, fractional_digits: usize) -> String {
+    if fractional_digits == 0 {
+        return String::new();
+    }
+    format!(".{:06}", micros)[..fractional_digits + 1].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::command_line_args::{BinaryEncoding, JsonColumnMode, NumericMode};
+
+    fn options() -> ValueConversionOptions {
+        ValueConversionOptions {
+            numeric_mode: NumericMode::Number,
+            binary_encoding: BinaryEncoding::Base64,
+            json_column_mode: JsonColumnMode::Nested,
+            temporal_mode: TemporalMode::Iso8601,
+            source_time_zone: TimeZone::UTC,
+            zero_date_policy: ZeroDatePolicy::Null,
+        }
+    }
+
+    fn field(column_type: Type, decimals: u32) -> Field {
+        Field {
+            r#type: column_type.into(),
+            decimals,
+            ..Default::default()
+        }
+    }
+
+    fn convert(value: &str, column_type: Type, decimals: u32) -> serde_json::Value {
+        temporal_to_json(value, &field(column_type, decimals), &options()).unwrap()
+    }
+
+    #[test]
+    fn applies_the_zero_date_policy() {
+        let with_policy = |zero_date_policy| ValueConversionOptions {
+            zero_date_policy,
+            ..options()
+        };
+        for (value, column_type) in [
+            ("0000-00-00", Type::Date),
+            ("0000-00-00 00:00:00", Type::Datetime),
+            ("2024-00-10 00:00:00", Type::Timestamp),
+            ("2024-05-00", Type::Date),
+        ] {
+            let field = field(column_type, 0);
+            assert_eq!(
+                temporal_to_json(value, &field, &with_policy(ZeroDatePolicy::Null)).unwrap(),
+                serde_json::Value::Null
+            );
+            assert_eq!(
+                temporal_to_json(value, &field, &with_policy(ZeroDatePolicy::Raw)).unwrap(),
+                json!(value)
+            );
+            let error =
+                temporal_to_json(value, &field, &with_policy(ZeroDatePolicy::Fail)).unwrap_err();
+            assert!(error.zero_date);
+            assert_eq!(error.value, value);
+        }
+
+        // An impossible date is not a zero date
+        let error = temporal_to_json(
+            "2024-02-30",
+            &field(Type::Date, 0),
+            &with_policy(ZeroDatePolicy::Null),
+        )
+        .unwrap_err();
+        assert!(!error.zero_date);
+    }
+
+    #[test]
+    fn converts_datetimes_in_the_source_time_zone() {
+        let new_york = ValueConversionOptions {
+            source_time_zone: TimeZone::posix("EST5EDT,M3.2.0,M11.1.0").unwrap(),
+            ..options()
+        };
+        let convert = |value, column_type| {
+            temporal_to_json(value, &field(column_type, 0), &new_york).unwrap()
+        };
+
+        assert_eq!(
+            convert("2024-01-15 12:00:00", Type::Datetime),
+            json!("2024-01-15T17:00:00Z")
+        );
+        assert_eq!(
+            convert("2024-07-15 12:00:00", Type::Datetime),
+            json!("2024-07-15T16:00:00Z")
+        );
+        // 02:30 doesn't exist when clocks move forward, it's read with the offset before the gap
+        assert_eq!(
+            convert("2024-03-10 02:30:00", Type::Datetime),
+            json!("2024-03-10T07:30:00Z")
+        );
+        // 01:30 happens twice when clocks move back, the earlier one wins
+        assert_eq!(
+            convert("2024-11-03 01:30:00", Type::Datetime),
+            json!("2024-11-03T05:30:00Z")
+        );
+        // Vstream formats timestamps in UTC whatever the source time zone is
+        assert_eq!(
+            convert("2024-01-15 12:00:00", Type::Timestamp),
+            json!("2024-01-15T12:00:00Z")
+        );
+        // Dates are calendar days, not instants
+        assert_eq!(convert("2024-01-15", Type::Date), json!("2024-01-15"));
+    }
+
+    #[test]
+    fn converts_times() {
+        assert_eq!(convert("03:04:05", Type::Time, 0), json!("PT3H4M5S"));
+        assert_eq!(convert("838:59:59", Type::Time, 0), json!("PT838H59M59S"));
+        assert_eq!(
+            convert("-838:59:59.000000", Type::Time, 6),
+            json!("-PT838H59M59.000000S")
+        );
+        assert_eq!(convert("-00:00:01.5", Type::Time, 1), json!("-PT0H0M1.5S"));
+
+        let micros = ValueConversionOptions {
+            temporal_mode: TemporalMode::EpochMicros,
+            ..options()
+        };
+        assert_eq!(
+            temporal_to_json("-838:59:59", &field(Type::Time, 0), &micros).unwrap(),
+            json!(-3_020_399_000_000i64)
+        );
+        let millis = ValueConversionOptions {
+            temporal_mode: TemporalMode::EpochMillis,
+            ..options()
+        };
+        assert_eq!(
+            temporal_to_json("00:00:01.25", &field(Type::Time, 2), &millis).unwrap(),
+            json!(1250)
+        );
+
+        assert!(temporal_to_json("12:34", &field(Type::Time, 0), &options()).is_err());
+    }
+
+    #[test]
+    fn truncates_fractional_digits_to_the_column() {
+        let value = "2024-01-02 03:04:05.123456";
+        assert_eq!(
+            convert(value, Type::Datetime, 0),
+            json!("2024-01-02T03:04:05Z")
+        );
+        assert_eq!(
+            convert(value, Type::Datetime, 3),
+            json!("2024-01-02T03:04:05.123Z")
+        );
+        assert_eq!(
+            convert(value, Type::Datetime, 6),
+            json!("2024-01-02T03:04:05.123456Z")
+        );
+        // Expressions report 31 decimals
+        assert_eq!(
+            convert(value, Type::Datetime, 31),
+            json!("2024-01-02T03:04:05.123456Z")
+        );
+        assert_eq!(
+            convert("2024-01-02 03:04:05.1", Type::Timestamp, 2),
+            json!("2024-01-02T03:04:05.10Z")
+        );
+        assert_eq!(
+            convert("10:00:00.987654", Type::Time, 2),
+            json!("PT10H0M0.98S")
+        );
+    }
+
+    #[test]
+    fn converts_to_epochs() {
+        let with_mode = |temporal_mode| ValueConversionOptions {
+            temporal_mode,
+            ..options()
+        };
+        let value = "1970-01-02 00:00:00.5";
+        let field = field(Type::Datetime, 1);
+        assert_eq!(
+            temporal_to_json(value, &field, &with_mode(TemporalMode::EpochMillis)).unwrap(),
+            json!(86_400_500)
+        );
+        assert_eq!(
+            temporal_to_json(value, &field, &with_mode(TemporalMode::EpochMicros)).unwrap(),
+            json!(86_400_500_000i64)
+        );
+        assert_eq!(
+            temporal_to_json(
+                "1969-12-31",
+                &Field {
+                    r#type: Type::Date.into(),
+                    ..Default::default()
+                },
+                &with_mode(TemporalMode::EpochMillis)
+            )
+            .unwrap(),
+            json!(-86_400_000)
+        );
+    }
+}
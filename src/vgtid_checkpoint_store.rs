@@ -0,0 +1,105 @@
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    fs,
+    io::ErrorKind,
+    path::PathBuf,
+};
+
+use prost::Message;
+
+use crate::{command_line_args::Args, vitess_grpc::binlogdata::VGtid};
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct CheckpointStoreError {
+    pub location: Box<String>,
+    pub kind: CheckpointStoreErrorKind,
+}
+
+impl Display for CheckpointStoreError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "error accessing vgtid checkpoint `{}`", self.location)
+    }
+}
+
+impl Error for CheckpointStoreError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self.kind {
+            CheckpointStoreErrorKind::ReadFailed(e) => Some(e),
+            CheckpointStoreErrorKind::WriteFailed(e) => Some(e),
+            CheckpointStoreErrorKind::DecodeFailed(e) => Some(e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CheckpointStoreErrorKind {
+    ReadFailed(std::io::Error),
+    WriteFailed(std::io::Error),
+    DecodeFailed(prost::DecodeError),
+}
+
+pub(crate) trait VGtidCheckpointStore: Send {
+    fn load(&self) -> Result<Option<VGtid>, CheckpointStoreError>;
+    fn save(&mut self, vgtid: &VGtid) -> Result<(), CheckpointStoreError>;
+}
+
+pub(crate) fn create_checkpoint_store(args: &Args) -> Box<dyn VGtidCheckpointStore> {
+    match &args.checkpoint_file {
+        Some(path) => Box::new(FileVGtidCheckpointStore { path: path.clone() }),
+        None => {
+            log::warn!(
+                "No checkpoint store configured, the vstream will start from the current position on every restart"
+            );
+            Box::new(NoopVGtidCheckpointStore {})
+        }
+    }
+}
+
+pub(crate) struct FileVGtidCheckpointStore {
+    path: PathBuf,
+}
+
+impl FileVGtidCheckpointStore {
+    fn error(&self, kind: CheckpointStoreErrorKind) -> CheckpointStoreError {
+        CheckpointStoreError {
+            location: Box::new(self.path.display().to_string()),
+            kind,
+        }
+    }
+}
+
+impl VGtidCheckpointStore for FileVGtidCheckpointStore {
+    fn load(&self) -> Result<Option<VGtid>, CheckpointStoreError> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(self.error(CheckpointStoreErrorKind::ReadFailed(e))),
+        };
+
+        VGtid::decode(bytes.as_slice())
+            .map(Some)
+            .map_err(|e| self.error(CheckpointStoreErrorKind::DecodeFailed(e)))
+    }
+
+    fn save(&mut self, vgtid: &VGtid) -> Result<(), CheckpointStoreError> {
+        // Write to a temporary file first so a crash mid-write never leaves a truncated checkpoint
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, vgtid.encode_to_vec())
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| self.error(CheckpointStoreErrorKind::WriteFailed(e)))
+    }
+}
+
+pub(crate) struct NoopVGtidCheckpointStore {}
+
+impl VGtidCheckpointStore for NoopVGtidCheckpointStore {
+    fn load(&self) -> Result<Option<VGtid>, CheckpointStoreError> {
+        Ok(None)
+    }
+
+    fn save(&mut self, _vgtid: &VGtid) -> Result<(), CheckpointStoreError> {
+        Ok(())
+    }
+}
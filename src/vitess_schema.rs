@@ -5,8 +5,12 @@ use std::fmt::{self, Display, Formatter};
 use crate::vitess_grpc::tabletmanagerdata::TableDefinition;
 use crate::vitess_grpc::vtctldata::GetSchemaRequest;
 use crate::vitess_shards::KeyspaceName;
+use crate::vitess_table_filter::{TableRule, TableSelector};
 use crate::{
-    vitess_grpc::{query::Field, vtctlservice::vtctld_client::VtctldClient},
+    vitess_grpc::{
+        query::{Field, MySqlFlag},
+        vtctlservice::vtctld_client::VtctldClient,
+    },
     vitess_shards,
 };
 
@@ -43,6 +47,70 @@ pub(crate) struct VitessSchema {
     pub(crate) primary_keys: Vec<String>,
 }
 
+impl VitessSchema {
+    pub(crate) fn primary_key_fields(&self) -> Vec<(usize, &FieldName, &Field)> {
+        self.primary_keys
+            .iter()
+            .filter_map(|pk| {
+                self.schema
+                    .iter()
+                    .enumerate()
+                    .find(|(_, (field_name, _))| field_name.0 == *pk)
+                    .map(|(pos, (field_name, field))| (pos, field_name, field))
+            })
+            .collect()
+    }
+
+    /// Schema from a vstream FIELD event. Fields only carry the primary key flag when vttablet
+    /// reports it, otherwise the primary keys of `previous` that still exist are kept.
+    pub(crate) fn from_field_event(
+        table: TableName,
+        fields: &[Field],
+        previous: Option<&VitessSchema>,
+    ) -> VitessSchema {
+        let flagged_primary_keys: Vec<String> = fields
+            .iter()
+            .filter(|field| field.flags & MySqlFlag::PriKeyFlag as u32 != 0)
+            .map(|field| field.name.clone())
+            .collect();
+        let primary_keys = if flagged_primary_keys.is_empty() {
+            previous
+                .map(|previous| {
+                    previous
+                        .primary_keys
+                        .iter()
+                        .filter(|pk| fields.iter().any(|field| field.name == **pk))
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default()
+        } else {
+            flagged_primary_keys
+        };
+
+        VitessSchema {
+            table,
+            schema: fields
+                .iter()
+                .map(|field| (FieldName(field.name.clone()), field.clone()))
+                .collect(),
+            primary_keys,
+        }
+    }
+
+    /// Whether rows of both schemas decode the same way
+    pub(crate) fn has_same_columns(&self, other: &VitessSchema) -> bool {
+        self.schema.len() == other.schema.len()
+            && self.schema.iter().zip(other.schema.iter()).all(
+                |((name, field), (other_name, other_field))| {
+                    name == other_name
+                        && field.r#type == other_field.r#type
+                        && field.column_type == other_field.column_type
+                },
+            )
+    }
+}
+
 #[derive(Debug)]
 #[non_exhaustive]
 pub struct VitessSchemaError {
@@ -90,7 +158,7 @@ impl Error for VitessSchemasNotFoundError {}
 pub(crate) async fn get_schema_for_tables(
     vtctld_client: &mut VtctldClient<tonic::transport::Channel>,
     keyspace: &KeyspaceName,
-    tables: &[TableName],
+    tables: &[TableRule],
 ) -> Result<HashMap<TableName, VitessSchema>, VitessSchemaError> {
     log::info!("Finding primary tablet...");
     let primary_tablet =
@@ -107,7 +175,7 @@ pub(crate) async fn get_schema_for_tables(
     );
     let schema_response = vtctld_client
         .get_schema(tonic::Request::new(GetSchemaRequest {
-            tables: tables.iter().map(|t| t.to_string()).collect(),
+            tables: tables.iter().map(|t| t.selector.schema_pattern()).collect(),
             tablet_alias: primary_tablet.alias.clone(),
             table_names_only: false,
             table_sizes_only: false,
@@ -132,7 +200,15 @@ pub(crate) async fn get_schema_for_tables(
 
     log::info!("All schemas = {:?}", schemas);
 
-    validate_all_schemas_present(keyspace, tables, schemas)
+    // Patterns are allowed to match nothing, only exact table names have to exist
+    let table_names: Vec<TableName> = tables
+        .iter()
+        .filter_map(|t| match &t.selector {
+            TableSelector::Name(table) => Some(table.clone()),
+            TableSelector::Pattern(_) => None,
+        })
+        .collect();
+    validate_all_schemas_present(keyspace, &table_names, schemas)
 }
 
 fn validate_all_schemas_present(
@@ -0,0 +1,318 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    vitess_grpc::{binlogdata::VGtid, query::Field},
+    vitess_schema::{TableName, VitessSchema},
+    vitess_shards::KeyspaceName,
+};
+
+pub(crate) type SchemaVersion = u64;
+
+#[derive(Debug)]
+pub(crate) struct VersionedSchema {
+    pub(crate) version: SchemaVersion,
+    /// Stream position the schema applies from, `None` for the schema fetched at startup
+    pub(crate) position: Option<VGtid>,
+    pub(crate) schema: VitessSchema,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+struct ShardTable {
+    keyspace: KeyspaceName,
+    shard: String,
+    table: TableName,
+}
+
+#[derive(Default)]
+struct Schemas {
+    tables: HashMap<TableName, Vec<Arc<VersionedSchema>>>,
+    // Shards apply DDLs one after the other, each streams with the version of its own last FIELD
+    // event until it migrated as well
+    shard_versions: HashMap<ShardTable, SchemaVersion>,
+}
+
+// Every table schema seen so far. The listener registers a new version whenever a FIELD event
+// changes the columns of a table and stamps each row with the version its shard streamed it with,
+// the converter runs behind the listener and looks up that exact version to decode the row.
+#[derive(Clone)]
+pub(crate) struct SchemaRegistry {
+    schemas: Arc<RwLock<Schemas>>,
+}
+
+impl SchemaRegistry {
+    pub(crate) fn new(schemas: HashMap<TableName, VitessSchema>) -> SchemaRegistry {
+        SchemaRegistry {
+            schemas: Arc::new(RwLock::new(Schemas {
+                tables: schemas
+                    .into_iter()
+                    .map(|(table, schema)| {
+                        (
+                            table,
+                            vec![Arc::new(VersionedSchema {
+                                version: 0,
+                                position: None,
+                                schema,
+                            })],
+                        )
+                    })
+                    .collect(),
+                shard_versions: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Tables whose schema was fetched at startup, FIELD events register the other ones
+    pub(crate) fn startup_tables(&self) -> Vec<TableName> {
+        self.schemas
+            .read()
+            .expect("Schema registry lock should not be poisoned")
+            .tables
+            .iter()
+            .filter(|(_, versions)| versions.first().is_some_and(|v| v.position.is_none()))
+            .map(|(table, _)| table.clone())
+            .collect()
+    }
+
+    /// Every table with a schema, whether fetched at startup or registered by a FIELD event
+    pub(crate) fn tables(&self) -> Vec<TableName> {
+        self.schemas
+            .read()
+            .expect("Schema registry lock should not be poisoned")
+            .tables
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    /// Newest version of the table on any shard
+    pub(crate) fn latest(&self, table: &TableName) -> Option<Arc<VersionedSchema>> {
+        self.schemas
+            .read()
+            .expect("Schema registry lock should not be poisoned")
+            .tables
+            .get(table)
+            .and_then(|versions| versions.last().cloned())
+    }
+
+    /// Version the shard streams the table with, the startup schema until the shard sent a FIELD
+    /// event for it
+    pub(crate) fn current(
+        &self,
+        keyspace: &KeyspaceName,
+        shard: &str,
+        table: &TableName,
+    ) -> Option<Arc<VersionedSchema>> {
+        let schemas = self
+            .schemas
+            .read()
+            .expect("Schema registry lock should not be poisoned");
+        let versions = schemas.tables.get(table)?;
+        let shard_version = schemas.shard_versions.get(&ShardTable {
+            keyspace: keyspace.clone(),
+            shard: shard.to_string(),
+            table: table.clone(),
+        });
+        match shard_version {
+            Some(version) => versions.iter().find(|v| v.version == *version).cloned(),
+            None => versions.first().cloned(),
+        }
+    }
+
+    pub(crate) fn get(
+        &self,
+        table: &TableName,
+        version: SchemaVersion,
+    ) -> Option<Arc<VersionedSchema>> {
+        self.schemas
+            .read()
+            .expect("Schema registry lock should not be poisoned")
+            .tables
+            .get(table)
+            .and_then(|versions| versions.iter().find(|v| v.version == version).cloned())
+    }
+
+    /// Registers the schema of a FIELD event from `shard`, returning the version the shard now
+    /// streams the table with
+    pub(crate) fn on_field_event(
+        &self,
+        keyspace: &KeyspaceName,
+        shard: &str,
+        table: &TableName,
+        fields: &[Field],
+        position: &VGtid,
+    ) -> SchemaVersion {
+        let mut schemas = self
+            .schemas
+            .write()
+            .expect("Schema registry lock should not be poisoned");
+        let Schemas {
+            tables,
+            shard_versions,
+        } = &mut *schemas;
+        let shard_table = ShardTable {
+            keyspace: keyspace.clone(),
+            shard: shard.to_string(),
+            table: table.clone(),
+        };
+        let versions = tables.entry(table.clone()).or_default();
+        let previous = shard_versions
+            .get(&shard_table)
+            .and_then(|version| versions.iter().find(|v| v.version == *version))
+            .or(versions.last());
+        let schema =
+            VitessSchema::from_field_event(table.clone(), fields, previous.map(|v| &v.schema));
+
+        // Shards that migrate later reuse the version the first one registered
+        let version = match versions
+            .iter()
+            .rev()
+            .find(|v| v.schema.has_same_columns(&schema))
+        {
+            Some(existing) => existing.version,
+            None => {
+                let version = versions.last().map(|v| v.version + 1).unwrap_or_default();
+                log::info!(
+                    "Schema of {} changed on shard {}, registering version {}: {:?}",
+                    table,
+                    shard,
+                    version,
+                    schema
+                );
+                versions.push(Arc::new(VersionedSchema {
+                    version,
+                    position: Some(position.clone()),
+                    schema,
+                }));
+                version
+            }
+        };
+        shard_versions.insert(shard_table, version);
+        version
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vitess_grpc::query::Type;
+
+    fn fields(names: &[&str]) -> Vec<Field> {
+        names
+            .iter()
+            .map(|name| Field {
+                name: name.to_string(),
+                r#type: Type::Int64.into(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tracks_the_version_of_every_shard() {
+        let table: TableName = "orders".to_string().into();
+        let keyspace: KeyspaceName = "commerce".to_string().into();
+        let registry = SchemaRegistry::new(HashMap::from([(
+            table.clone(),
+            VitessSchema::from_field_event(table.clone(), &fields(&["id"]), None),
+        )]));
+        let position = VGtid::default();
+
+        assert_eq!(
+            registry.on_field_event(&keyspace, "-80", &table, &fields(&["id"]), &position),
+            0
+        );
+        assert_eq!(
+            registry.on_field_event(&keyspace, "80-", &table, &fields(&["id"]), &position),
+            0
+        );
+
+        // The DDL reached the first shard only
+        assert_eq!(
+            registry.on_field_event(
+                &keyspace,
+                "-80",
+                &table,
+                &fields(&["id", "total"]),
+                &position
+            ),
+            1
+        );
+        assert_eq!(
+            registry.current(&keyspace, "-80", &table).unwrap().version,
+            1
+        );
+        assert_eq!(
+            registry.current(&keyspace, "80-", &table).unwrap().version,
+            0
+        );
+        assert_eq!(registry.latest(&table).unwrap().version, 1);
+
+        // The second shard reuses the version once it migrated
+        assert_eq!(
+            registry.on_field_event(
+                &keyspace,
+                "80-",
+                &table,
+                &fields(&["id", "total"]),
+                &position
+            ),
+            1
+        );
+        assert_eq!(
+            registry.current(&keyspace, "80-", &table).unwrap().version,
+            1
+        );
+    }
+
+    #[test]
+    fn uses_the_startup_schema_before_a_field_event() {
+        let table: TableName = "orders".to_string().into();
+        let keyspace: KeyspaceName = "commerce".to_string().into();
+        let registry = SchemaRegistry::new(HashMap::from([(
+            table.clone(),
+            VitessSchema::from_field_event(table.clone(), &fields(&["id"]), None),
+        )]));
+        registry.on_field_event(
+            &keyspace,
+            "-80",
+            &table,
+            &fields(&["id", "total"]),
+            &VGtid::default(),
+        );
+
+        assert_eq!(
+            registry.current(&keyspace, "80-", &table).unwrap().version,
+            0
+        );
+        assert!(
+            registry
+                .current(&keyspace, "-80", &"customers".to_string().into())
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn lists_tables_registered_by_field_events() {
+        let table: TableName = "orders".to_string().into();
+        let created: TableName = "invoices".to_string().into();
+        let registry = SchemaRegistry::new(HashMap::from([(
+            table.clone(),
+            VitessSchema::from_field_event(table.clone(), &fields(&["id"]), None),
+        )]));
+        registry.on_field_event(
+            &"commerce".to_string().into(),
+            "-80",
+            &created,
+            &fields(&["id"]),
+            &VGtid::default(),
+        );
+
+        let mut tables = registry.tables();
+        tables.sort_by_key(|table| table.to_string());
+        assert_eq!(tables, vec![created, table.clone()]);
+        assert_eq!(registry.startup_tables(), vec![table]);
+    }
+}
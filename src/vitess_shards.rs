@@ -1,7 +1,10 @@
-use std::fmt::Display;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
 
 use crate::vitess_grpc::{
-    binlogdata::ShardGtid,
+    binlogdata::{Journal, MigrationType, ShardGtid, VGtid},
     topodata::{Tablet, TabletType},
     vtctldata::{FindAllShardsInKeyspaceRequest, GetTabletsRequest},
     vtctlservice::vtctld_client::VtctldClient,
@@ -53,6 +56,23 @@ pub(crate) async fn get_current_shard_gtids(
     Ok(shard_gtids)
 }
 
+pub(crate) async fn get_copy_shard_gtids(
+    vtctld_client: &mut VtctldClient<tonic::transport::Channel>,
+    keyspace: &KeyspaceName,
+) -> Result<Vec<ShardGtid>, tonic::Status> {
+    // An empty gtid asks vstream to copy every table matched by the filter before streaming
+    Ok(get_all_shard_names(vtctld_client, keyspace)
+        .await?
+        .into_iter()
+        .map(|shard| ShardGtid {
+            keyspace: keyspace.to_string(),
+            shard,
+            gtid: String::new(),
+            table_p_ks: Vec::new(),
+        })
+        .collect())
+}
+
 pub(crate) async fn get_all_shard_names<'a>(
     vtctld_client: &'a mut VtctldClient<tonic::transport::Channel>,
     keyspace: &KeyspaceName,
@@ -71,6 +91,77 @@ pub(crate) async fn get_all_shard_names<'a>(
         .collect())
 }
 
+/// Position to continue from once a journal shows the participants stopped serving `tables`.
+/// Returns `None` if the journal does not change which shards we have to stream from.
+pub(crate) fn follow_journal(vgtid: &VGtid, journal: &Journal, tables: &[String]) -> Option<VGtid> {
+    let is_participant = |shard_gtid: &ShardGtid| {
+        journal.participants.iter().any(|participant| {
+            participant.keyspace == shard_gtid.keyspace && participant.shard == shard_gtid.shard
+        })
+    };
+    if !vgtid.shard_gtids.iter().any(is_participant) {
+        return None;
+    }
+
+    // A reshard retires the source shards entirely, MoveTables only moves some tables away, so
+    // the source shards are only dropped once none of the replicated tables are left on them
+    let retire_participants = match journal.migration_type() {
+        MigrationType::Shards => true,
+        MigrationType::Tables => tables.iter().all(|table| journal.tables.contains(table)),
+    };
+
+    let mut shard_gtids: Vec<ShardGtid> = vgtid
+        .shard_gtids
+        .iter()
+        .filter(|shard_gtid| !retire_participants || !is_participant(shard_gtid))
+        .cloned()
+        .collect();
+    for new_shard_gtid in journal.shard_gtids.iter() {
+        if !shard_gtids.iter().any(|shard_gtid| {
+            shard_gtid.keyspace == new_shard_gtid.keyspace
+                && shard_gtid.shard == new_shard_gtid.shard
+        }) {
+            shard_gtids.push(new_shard_gtid.clone());
+        }
+    }
+
+    Some(VGtid { shard_gtids })
+}
+
+// Every participant sends the journal when it stops, the stream only moves once all participants
+// it reads from did so, otherwise the events the others still stream before their journal are lost
+#[derive(Default)]
+pub(crate) struct JournalTracker {
+    delivered: HashMap<i64, HashSet<(String, String)>>,
+}
+
+impl JournalTracker {
+    /// Records that `keyspace/shard` delivered the journal, returning the position to continue
+    /// from once the last participant in `vgtid` delivered it
+    pub(crate) fn on_journal(
+        &mut self,
+        vgtid: &VGtid,
+        journal: &Journal,
+        keyspace: &str,
+        shard: &str,
+        tables: &[String],
+    ) -> Option<VGtid> {
+        let delivered = self.delivered.entry(journal.id).or_default();
+        delivered.insert((keyspace.to_string(), shard.to_string()));
+        let waiting = vgtid.shard_gtids.iter().any(|shard_gtid| {
+            journal.participants.iter().any(|participant| {
+                participant.keyspace == shard_gtid.keyspace && participant.shard == shard_gtid.shard
+            }) && !delivered.contains(&(shard_gtid.keyspace.clone(), shard_gtid.shard.clone()))
+        });
+        if waiting {
+            return None;
+        }
+
+        self.delivered.remove(&journal.id);
+        follow_journal(vgtid, journal, tables)
+    }
+}
+
 pub(crate) async fn get_first_primary_tablet_for_first_shard<'a>(
     vtctld_client: &'a mut VtctldClient<tonic::transport::Channel>,
     keyspace: &KeyspaceName,
@@ -99,3 +190,141 @@ pub(crate) async fn get_first_primary_tablet_for_first_shard<'a>(
         .expect("There should be at least one primary tablet")
         .clone())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vitess_grpc::binlogdata::KeyspaceShard;
+
+    fn shard_gtid(keyspace: &str, shard: &str, gtid: &str) -> ShardGtid {
+        ShardGtid {
+            keyspace: keyspace.to_string(),
+            shard: shard.to_string(),
+            gtid: gtid.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn journal(
+        migration_type: MigrationType,
+        tables: &[&str],
+        participants: &[(&str, &str)],
+        shard_gtids: Vec<ShardGtid>,
+    ) -> Journal {
+        Journal {
+            id: 7,
+            migration_type: migration_type.into(),
+            tables: tables.iter().map(|table| table.to_string()).collect(),
+            participants: participants
+                .iter()
+                .map(|(keyspace, shard)| KeyspaceShard {
+                    keyspace: keyspace.to_string(),
+                    shard: shard.to_string(),
+                })
+                .collect(),
+            shard_gtids,
+            ..Default::default()
+        }
+    }
+
+    fn shards(vgtid: &VGtid) -> Vec<(&str, &str)> {
+        vgtid
+            .shard_gtids
+            .iter()
+            .map(|shard_gtid| (shard_gtid.keyspace.as_str(), shard_gtid.shard.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn reshard_replaces_the_source_shards() {
+        let vgtid = VGtid {
+            shard_gtids: vec![shard_gtid("commerce", "0", "MySQL56/a:1-10")],
+        };
+        let journal = journal(
+            MigrationType::Shards,
+            &[],
+            &[("commerce", "0")],
+            vec![
+                shard_gtid("commerce", "-80", "MySQL56/b:1-5"),
+                shard_gtid("commerce", "80-", "MySQL56/c:1-3"),
+            ],
+        );
+
+        let followed = follow_journal(&vgtid, &journal, &["orders".to_string()]).unwrap();
+        assert_eq!(
+            shards(&followed),
+            [("commerce", "-80"), ("commerce", "80-")]
+        );
+        assert_eq!(followed.shard_gtids[0].gtid, "MySQL56/b:1-5");
+    }
+
+    #[test]
+    fn partial_move_tables_keeps_the_source_shards() {
+        let vgtid = VGtid {
+            shard_gtids: vec![shard_gtid("commerce", "0", "MySQL56/a:1-10")],
+        };
+        let journal = journal(
+            MigrationType::Tables,
+            &["orders"],
+            &[("commerce", "0")],
+            vec![shard_gtid("customer", "0", "MySQL56/b:1-5")],
+        );
+
+        let tables = ["orders".to_string(), "customers".to_string()];
+        let followed = follow_journal(&vgtid, &journal, &tables).unwrap();
+        assert_eq!(shards(&followed), [("commerce", "0"), ("customer", "0")]);
+
+        // Once every replicated table moved the source is retired
+        let followed = follow_journal(&vgtid, &journal, &tables[..1]).unwrap();
+        assert_eq!(shards(&followed), [("customer", "0")]);
+    }
+
+    #[test]
+    fn ignores_journals_of_other_shards() {
+        let vgtid = VGtid {
+            shard_gtids: vec![shard_gtid("commerce", "0", "MySQL56/a:1-10")],
+        };
+        let journal = journal(
+            MigrationType::Shards,
+            &[],
+            &[("customer", "0")],
+            vec![shard_gtid("customer", "-80", "MySQL56/b:1-5")],
+        );
+
+        assert!(follow_journal(&vgtid, &journal, &[]).is_none());
+    }
+
+    #[test]
+    fn waits_for_every_participant() {
+        let vgtid = VGtid {
+            shard_gtids: vec![
+                shard_gtid("commerce", "-80", "MySQL56/a:1-10"),
+                shard_gtid("commerce", "80-", "MySQL56/b:1-10"),
+            ],
+        };
+        let journal = journal(
+            MigrationType::Shards,
+            &[],
+            &[("commerce", "-80"), ("commerce", "80-")],
+            vec![shard_gtid("commerce", "-", "MySQL56/c:1-5")],
+        );
+        let mut tracker = JournalTracker::default();
+
+        assert!(
+            tracker
+                .on_journal(&vgtid, &journal, "commerce", "-80", &[])
+                .is_none()
+        );
+        // Delivering it again on the same shard does not complete it
+        assert!(
+            tracker
+                .on_journal(&vgtid, &journal, "commerce", "-80", &[])
+                .is_none()
+        );
+        let followed = tracker
+            .on_journal(&vgtid, &journal, "commerce", "80-", &[])
+            .unwrap();
+        assert_eq!(shards(&followed), [("commerce", "-")]);
+        assert!(tracker.delivered.is_empty());
+    }
+}
@@ -1,22 +1,32 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     error::Error,
     fmt::{self, Display, Formatter},
-    sync::mpsc::SendError,
+    sync::{
+        Arc,
+        mpsc::{Receiver, SendError, Sender, TryRecvError},
+    },
+    time::{SystemTime, UNIX_EPOCH},
 };
 
+use tokio::sync::oneshot::{self, error::RecvError};
 use tokio_stream::StreamExt;
 use tonic::Streaming;
 
 use crate::{
-    replication_row_event::ReplicationRowEventEnvelope,
+    command_line_args::Args,
+    replication_row_event::{ReplicationRowEvent, ReplicationRowEventEnvelope, TransactionContext},
+    table_row_deserializer::raw_row_values,
     vitess_grpc::{
-        query::BoundQuery,
-        vtgate::{StreamExecuteRequest, StreamExecuteResponse},
+        query::{BindVariable, BoundQuery, Row, Type},
+        vtctlservice::vtctld_client::VtctldClient,
+        vtgate::{ExecuteRequest, Session, StreamExecuteRequest, StreamExecuteResponse},
         vtgateservice::vitess_client::VitessClient,
     },
     vitess_schema::{TableName, VitessSchema},
-    vitess_shards::KeyspaceName,
+    vitess_schema_registry::{SchemaRegistry, SchemaVersion, VersionedSchema},
+    vitess_shards::{KeyspaceName, get_all_shard_names},
+    vitess_table_filter::{TableRule, TableSelector},
 };
 
 #[derive(Debug)]
@@ -31,8 +41,8 @@ impl Display for VitessSnapshotError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "error while taking incremental snapshot `{}`",
-            self.keyspace
+            "error while taking incremental snapshot `{}.{}`",
+            self.keyspace, self.table
         )
     }
 }
@@ -41,7 +51,9 @@ impl Error for VitessSnapshotError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match &self.kind {
             VitessSnapshotErrorKind::VitessClientCallFailed(e) => Some(e),
+            VitessSnapshotErrorKind::WatermarkWriteFailed(e) => Some(e),
             VitessSnapshotErrorKind::SendFailed(e) => Some(e),
+            VitessSnapshotErrorKind::ListenerStopped(e) => Some(e),
         }
     }
 }
@@ -49,7 +61,559 @@ impl Error for VitessSnapshotError {
 #[derive(Debug)]
 pub enum VitessSnapshotErrorKind {
     VitessClientCallFailed(tonic::Status),
-    SendFailed(SendError<ReplicationRowEventEnvelope>),
+    WatermarkWriteFailed(tonic::Status),
+    SendFailed(SendError<SnapshotSignal>),
+    /// The listener went away before it emitted a chunk
+    ListenerStopped(RecvError),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SnapshotConfig {
+    pub(crate) signal_table: TableName,
+    pub(crate) chunk_size: usize,
+    /// `WHERE` conditions of the table select filters, so the backfill only reads rows the
+    /// stream would emit
+    pub(crate) filters: HashMap<TableName, String>,
+}
+
+impl From<&Args> for SnapshotConfig {
+    fn from(args: &Args) -> Self {
+        SnapshotConfig {
+            signal_table: args.snapshot_signal_table.clone().into(),
+            chunk_size: args.snapshot_chunk_size,
+            filters: table_filters(&args.tables),
+        }
+    }
+}
+
+fn table_filters(rules: &[TableRule]) -> HashMap<TableName, String> {
+    rules
+        .iter()
+        .filter_map(|rule| match (&rule.selector, &rule.select) {
+            (TableSelector::Name(table), Some(select)) => {
+                Some((table.clone(), select.filter.clone()?))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+pub(crate) type PrimaryKeyValues = Vec<Option<Vec<u8>>>;
+
+pub(crate) enum SnapshotSignal {
+    ChunkStarted {
+        id: String,
+        table: TableName,
+        shards: Vec<String>,
+        /// Fired once the chunk's rows have been emitted
+        emitted: oneshot::Sender<()>,
+    },
+    ChunkRead {
+        id: String,
+        rows: Vec<(PrimaryKeyValues, Row)>,
+        /// Version of the schema whose columns the chunk selected
+        schema_version: SchemaVersion,
+    },
+    Completed,
+}
+
+struct PendingChunk {
+    table: TableName,
+    shards: Vec<String>,
+    rows: Option<Vec<(PrimaryKeyValues, Row)>>,
+    schema_version: SchemaVersion,
+    emitted: oneshot::Sender<()>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WatermarkKind {
+    Low,
+    High,
+}
+
+impl Display for WatermarkKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            WatermarkKind::Low => write!(f, "low"),
+            WatermarkKind::High => write!(f, "high"),
+        }
+    }
+}
+
+// Primary keys changed by the vstream while a chunk's watermark window is open. Any snapshot
+// row for one of these keys may be older than the change that was already emitted downstream.
+struct WatermarkWindow {
+    table: TableName,
+    high_watermark_shards: HashSet<String>,
+    changed_primary_keys: HashSet<PrimaryKeyValues>,
+}
+
+// DBLog style watermark processing, see https://arxiv.org/abs/2010.12597
+//
+// For every chunk the snapshot task announces the chunk, writes a low watermark to the signal
+// table on every shard, reads the chunk, hands it over to the listener and then writes a high
+// watermark on every shard. The listener opens a window on the first low watermark it observes,
+// records the keys of every change to the chunk's table while the window is open and once the
+// high watermark of every shard has been observed emits the chunk rows that were not changed in
+// the meantime. Opening on the first shard and closing on the last means changes on other shards
+// can only cause rows to be dropped conservatively, never to be emitted out of order.
+//
+// The snapshot task waits for a chunk to be emitted before it reads the next one, so at most one
+// chunk is held in memory however far the stream lags behind.
+pub(crate) struct SnapshotWatermarkTracker {
+    signal_table: Option<TableName>,
+    incoming_signals: Option<Receiver<SnapshotSignal>>,
+    pending_chunks: HashMap<String, PendingChunk>,
+    windows: HashMap<String, WatermarkWindow>,
+    snapshot_completed: bool,
+    stream_started: Option<oneshot::Sender<()>>,
+}
+
+impl SnapshotWatermarkTracker {
+    pub(crate) fn new(
+        signal_table: TableName,
+        incoming_signals: Receiver<SnapshotSignal>,
+        stream_started: oneshot::Sender<()>,
+    ) -> Self {
+        SnapshotWatermarkTracker {
+            signal_table: Some(signal_table),
+            incoming_signals: Some(incoming_signals),
+            pending_chunks: HashMap::new(),
+            windows: HashMap::new(),
+            snapshot_completed: false,
+            stream_started: Some(stream_started),
+        }
+    }
+
+    pub(crate) fn completed() -> Self {
+        SnapshotWatermarkTracker {
+            signal_table: None,
+            incoming_signals: None,
+            pending_chunks: HashMap::new(),
+            windows: HashMap::new(),
+            snapshot_completed: true,
+            stream_started: None,
+        }
+    }
+
+    // Chunks may only be read once the vstream position is established, a change committed
+    // after a chunk was read but before the stream started would otherwise be lost.
+    pub(crate) fn on_stream_started(&mut self) {
+        if let Some(stream_started) = self.stream_started.take() {
+            // The snapshot task may have failed already, which is reported by the task itself
+            let _ = stream_started.send(());
+        }
+    }
+
+    // Checkpoints are held back until every chunk has been emitted, a restart before that would
+    // resume streaming without ever finishing the backfill.
+    pub(crate) fn is_completed(&mut self) -> bool {
+        self.receive_signals();
+        self.snapshot_completed && self.pending_chunks.is_empty() && self.windows.is_empty()
+    }
+
+    pub(crate) fn is_signal_table(&self, table: &TableName) -> bool {
+        self.signal_table.as_ref() == Some(table)
+    }
+
+    pub(crate) fn is_tracking(&self, table: &TableName) -> bool {
+        self.windows.values().any(|window| window.table == *table)
+    }
+
+    pub(crate) fn record_change(&mut self, table: &TableName, primary_key: PrimaryKeyValues) {
+        for window in self.windows.values_mut() {
+            if window.table == *table {
+                window.changed_primary_keys.insert(primary_key.clone());
+            }
+        }
+    }
+
+    // Returns the snapshot rows to emit at the current position of the stream
+    pub(crate) fn on_signal_row(
+        &mut self,
+        keyspace: &KeyspaceName,
+        shard: &str,
+        timestamp: i64,
+        row: &Row,
+    ) -> Vec<ReplicationRowEventEnvelope> {
+        self.receive_signals();
+
+        let Some((id, kind)) = parse_watermark(row) else {
+            log::warn!("Ignoring unrecognised row in the snapshot signal table");
+            return vec![];
+        };
+
+        match kind {
+            WatermarkKind::Low => {
+                if !self.windows.contains_key(&id) {
+                    let Some(chunk) = self.pending_chunks.get(&id) else {
+                        log::debug!("Ignoring low watermark {} of an unknown chunk", id);
+                        return vec![];
+                    };
+                    log::debug!("Opening watermark window for chunk {}", id);
+                    self.windows.insert(
+                        id,
+                        WatermarkWindow {
+                            table: chunk.table.clone(),
+                            high_watermark_shards: HashSet::new(),
+                            changed_primary_keys: HashSet::new(),
+                        },
+                    );
+                }
+                vec![]
+            }
+            WatermarkKind::High => {
+                let Some(window) = self.windows.get_mut(&id) else {
+                    log::debug!("Ignoring high watermark {} without an open window", id);
+                    return vec![];
+                };
+                window.high_watermark_shards.insert(shard.to_string());
+
+                // The chunk is always read before its high watermarks are written
+                let all_shards_reported = self.pending_chunks.get(&id).is_some_and(|chunk| {
+                    chunk.rows.is_some()
+                        && chunk
+                            .shards
+                            .iter()
+                            .all(|shard| window.high_watermark_shards.contains(shard))
+                });
+                if !all_shards_reported {
+                    return vec![];
+                }
+
+                let window = self.windows.remove(&id).expect("Window was just found");
+                let chunk = self
+                    .pending_chunks
+                    .remove(&id)
+                    .expect("Chunk was just found");
+                let chunk_rows = chunk.rows.unwrap_or_default();
+                let chunk_size = chunk_rows.len();
+                let rows: Vec<ReplicationRowEventEnvelope> = chunk_rows
+                    .into_iter()
+                    .filter(|(primary_key, _)| !window.changed_primary_keys.contains(primary_key))
+                    .enumerate()
+                    .map(|(sequence, (_, row))| ReplicationRowEventEnvelope {
+                        keyspace: keyspace.clone(),
+                        table: chunk.table.clone(),
+                        event: ReplicationRowEvent::SnapshotRead(row),
+                        // Each chunk is emitted as one unit at the high watermark of `shard`
+                        transaction: TransactionContext {
+                            id: format!("snapshot:{}", id),
+                            shard: shard.to_string(),
+                            timestamp,
+                            sequence: sequence as u64,
+                        },
+                        schema_version: chunk.schema_version,
+                    })
+                    .collect();
+                log::debug!(
+                    "Closing watermark window for chunk {}, emitting {} of {} rows",
+                    id,
+                    rows.len(),
+                    chunk_size
+                );
+                // The snapshot task may have failed already, which is reported by the task itself
+                let _ = chunk.emitted.send(());
+                rows
+            }
+        }
+    }
+
+    fn receive_signals(&mut self) {
+        let Some(incoming_signals) = &self.incoming_signals else {
+            return;
+        };
+
+        loop {
+            match incoming_signals.try_recv() {
+                Ok(SnapshotSignal::ChunkStarted {
+                    id,
+                    table,
+                    shards,
+                    emitted,
+                }) => {
+                    self.pending_chunks.insert(
+                        id,
+                        PendingChunk {
+                            table,
+                            shards,
+                            rows: None,
+                            schema_version: 0,
+                            emitted,
+                        },
+                    );
+                }
+                Ok(SnapshotSignal::ChunkRead {
+                    id,
+                    rows,
+                    schema_version,
+                }) => {
+                    if let Some(chunk) = self.pending_chunks.get_mut(&id) {
+                        chunk.rows = Some(rows);
+                        chunk.schema_version = schema_version;
+                    }
+                }
+                Ok(SnapshotSignal::Completed) => self.snapshot_completed = true,
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    // Either the snapshot finished or it failed, and that is reported by its task
+                    self.incoming_signals = None;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn parse_watermark(row: &Row) -> Option<(String, WatermarkKind)> {
+    // The signal table has the columns (shard, watermark)
+    let watermark = raw_row_values(row).get(1).cloned().flatten()?;
+    let watermark = std::str::from_utf8(watermark).ok()?;
+    match watermark.rsplit_once(':')? {
+        (id, "low") => Some((id.to_string(), WatermarkKind::Low)),
+        (id, "high") => Some((id.to_string(), WatermarkKind::High)),
+        _ => None,
+    }
+}
+
+pub(crate) fn primary_key_values(row: &Row, schema: &VitessSchema) -> PrimaryKeyValues {
+    let row_values = raw_row_values(row);
+    schema
+        .primary_key_fields()
+        .into_iter()
+        .map(|(pos, _, _)| row_values.get(pos).cloned().flatten().map(<[u8]>::to_vec))
+        .collect()
+}
+
+pub(crate) async fn start_vitess_snapshot(
+    mut vtctld_client: VtctldClient<tonic::transport::Channel>,
+    mut vtgate_client: VitessClient<tonic::transport::Channel>,
+    keyspace: KeyspaceName,
+    schemas: SchemaRegistry,
+    config: SnapshotConfig,
+    stream_started: oneshot::Receiver<()>,
+    outgoing_signals: Sender<SnapshotSignal>,
+) -> Result<(), VitessSnapshotError> {
+    if stream_started.await.is_err() {
+        log::warn!("Vstream listener stopped before streaming, not taking a snapshot");
+        return Ok(());
+    }
+
+    let shards = get_all_shard_names(&mut vtctld_client, &keyspace)
+        .await
+        .map_err(|e| VitessSnapshotError {
+            keyspace: Box::new(keyspace.clone()),
+            table: Box::new(config.signal_table.clone()),
+            kind: VitessSnapshotErrorKind::VitessClientCallFailed(e),
+        })?;
+
+    let snapshot = TableSnapshot {
+        keyspace: &keyspace,
+        shards: &shards,
+        signal_table: &config.signal_table,
+        chunk_size: config.chunk_size,
+        filters: &config.filters,
+        schemas: &schemas,
+        outgoing_signals: &outgoing_signals,
+        run_id: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default(),
+    };
+
+    for table in schemas.startup_tables().iter() {
+        log::info!("Starting snapshot of {}.{}...", keyspace, table);
+        let row_count = snapshot.snapshot_table(&mut vtgate_client, table).await?;
+        log::info!(
+            "Finished snapshot of {}.{}, read {} rows",
+            keyspace,
+            table,
+            row_count
+        );
+    }
+
+    outgoing_signals
+        .send(SnapshotSignal::Completed)
+        .map_err(|e| VitessSnapshotError {
+            keyspace: Box::new(keyspace.clone()),
+            table: Box::new(config.signal_table.clone()),
+            kind: VitessSnapshotErrorKind::SendFailed(e),
+        })?;
+    log::info!("Snapshot of keyspace {} completed", keyspace);
+
+    Ok(())
+}
+
+struct TableSnapshot<'a> {
+    keyspace: &'a KeyspaceName,
+    shards: &'a [String],
+    signal_table: &'a TableName,
+    chunk_size: usize,
+    filters: &'a HashMap<TableName, String>,
+    schemas: &'a SchemaRegistry,
+    outgoing_signals: &'a Sender<SnapshotSignal>,
+    run_id: u128,
+}
+
+impl TableSnapshot<'_> {
+    fn error(&self, table: &TableName, kind: VitessSnapshotErrorKind) -> VitessSnapshotError {
+        VitessSnapshotError {
+            keyspace: Box::new(self.keyspace.clone()),
+            table: Box::new(table.clone()),
+            kind,
+        }
+    }
+
+    async fn snapshot_table(
+        &self,
+        client: &mut VitessClient<tonic::transport::Channel>,
+        table: &TableName,
+    ) -> Result<usize, VitessSnapshotError> {
+        if self.current_schema(table).schema.primary_keys.is_empty() {
+            log::warn!(
+                "Table {}.{} has no primary key, reading it in a single query",
+                self.keyspace,
+                table
+            );
+        }
+
+        let mut row_count = 0;
+        let mut last_pk: Option<Vec<BindVariable>> = None;
+        for chunk_number in 0.. {
+            let id = format!("{}-{}-{}", self.run_id, table, chunk_number);
+            // A DDL during the snapshot changes the columns the following chunks select
+            let versioned_schema = self.current_schema(table);
+            let schema = &versioned_schema.schema;
+
+            let (emitted_sender, emitted) = oneshot::channel();
+            self.outgoing_signals
+                .send(SnapshotSignal::ChunkStarted {
+                    id: id.clone(),
+                    table: table.clone(),
+                    shards: self.shards.to_vec(),
+                    emitted: emitted_sender,
+                })
+                .map_err(|e| self.error(table, VitessSnapshotErrorKind::SendFailed(e)))?;
+            self.write_watermark(client, table, &id, WatermarkKind::Low)
+                .await?;
+            let results = perform_snapshot_query(
+                client,
+                self.keyspace,
+                table,
+                schema,
+                self.filters.get(table).map(String::as_str),
+                self.chunk_size,
+                last_pk,
+            )
+            .await
+            .map_err(|e| self.error(table, VitessSnapshotErrorKind::VitessClientCallFailed(e)))?;
+            let rows = process_query_result(results).await.map_err(|e| {
+                self.error(table, VitessSnapshotErrorKind::VitessClientCallFailed(e))
+            })?;
+
+            let chunk_row_count = rows.len();
+            let rows: Vec<(PrimaryKeyValues, Row)> = rows
+                .into_iter()
+                .map(|row| (primary_key_values(&row, schema), row))
+                .collect();
+            last_pk = rows
+                .last()
+                .map(|(primary_key, _)| primary_key_bind_variables(primary_key, schema));
+
+            self.outgoing_signals
+                .send(SnapshotSignal::ChunkRead {
+                    id: id.clone(),
+                    rows,
+                    schema_version: versioned_schema.version,
+                })
+                .map_err(|e| self.error(table, VitessSnapshotErrorKind::SendFailed(e)))?;
+            self.write_watermark(client, table, &id, WatermarkKind::High)
+                .await?;
+            emitted
+                .await
+                .map_err(|e| self.error(table, VitessSnapshotErrorKind::ListenerStopped(e)))?;
+
+            row_count += chunk_row_count;
+            log::debug!(
+                "Read chunk {} of {} rows from {}.{}",
+                id,
+                chunk_row_count,
+                self.keyspace,
+                table
+            );
+            if is_last_chunk(chunk_row_count, self.chunk_size, schema) {
+                break;
+            }
+        }
+
+        Ok(row_count)
+    }
+
+    fn current_schema(&self, table: &TableName) -> Arc<VersionedSchema> {
+        self.schemas
+            .latest(table)
+            .expect("Snapshotted tables should be registered at startup")
+    }
+
+    // Watermarks are written to every shard by targeting it directly, so each shard's
+    // vstream observes them in order with the changes made on that shard.
+    async fn write_watermark(
+        &self,
+        client: &mut VitessClient<tonic::transport::Channel>,
+        table: &TableName,
+        id: &str,
+        kind: WatermarkKind,
+    ) -> Result<(), VitessSnapshotError> {
+        for shard in self.shards.iter() {
+            let sql = format!(
+                "INSERT INTO {} (shard, watermark) VALUES (:shard, :watermark) ON DUPLICATE KEY UPDATE watermark = VALUES(watermark)",
+                quote_identifier(&self.signal_table.to_string())
+            );
+            let bind_variables = HashMap::from([
+                ("shard".to_string(), varchar_bind_variable(shard)),
+                (
+                    "watermark".to_string(),
+                    varchar_bind_variable(&format!("{}:{}", id, kind)),
+                ),
+            ]);
+
+            let response = client
+                .execute(tonic::Request::new(ExecuteRequest {
+                    caller_id: None,
+                    session: Some(Session {
+                        autocommit: true,
+                        target_string: format!("{}:{}", self.keyspace, shard),
+                        ..Default::default()
+                    }),
+                    query: Some(BoundQuery {
+                        sql,
+                        bind_variables,
+                    }),
+                }))
+                .await
+                .map_err(|e| self.error(table, VitessSnapshotErrorKind::WatermarkWriteFailed(e)))?
+                .into_inner();
+
+            // Query errors are reported in the response body rather than as a grpc status
+            if let Some(rpc_error) = response.error {
+                return Err(self.error(
+                    table,
+                    VitessSnapshotErrorKind::WatermarkWriteFailed(tonic::Status::new(
+                        tonic::Code::from_i32(rpc_error.code),
+                        rpc_error.message,
+                    )),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// A chunk shorter than the limit reached the end of the table, tables without a primary key are
+// read in a single query
+fn is_last_chunk(chunk_row_count: usize, chunk_size: usize, schema: &VitessSchema) -> bool {
+    chunk_row_count < chunk_size || schema.primary_keys.is_empty()
 }
 
 pub(crate) async fn perform_snapshot_query(
@@ -57,42 +621,294 @@ pub(crate) async fn perform_snapshot_query(
     keyspace: &KeyspaceName,
     table: &TableName,
     schema: &VitessSchema,
+    filter: Option<&str>,
     chunk_size: usize,
-    last_pk: Option<String>,
+    last_pk: Option<Vec<BindVariable>>,
 ) -> Result<Streaming<StreamExecuteResponse>, tonic::Status> {
-    let pks = schema.primary_keys.join(",");
     let query_response = client
         .stream_execute(tonic::Request::new(StreamExecuteRequest {
             caller_id: None,
-            session: None,
-            query: Some(BoundQuery {
-                sql: format!(
-                    "SELECT * FROM {}.{} ORDER BY {} LIMIT {}",
-                    keyspace, table, pks, chunk_size,
-                ),
-                bind_variables: HashMap::new(),
-            }),
+            session: Some(snapshot_session()),
+            query: Some(build_snapshot_query(
+                keyspace, table, schema, filter, chunk_size, last_pk,
+            )),
         }))
         .await?;
 
     Ok(query_response.into_inner())
 }
 
-async fn process_query_result(
+// MySQL renders TIMESTAMP columns in the session time zone while the replication stream sends
+// them in UTC, read the snapshot in UTC as well so both decode the same way
+fn snapshot_session() -> Session {
+    Session {
+        autocommit: true,
+        system_variables: HashMap::from([("time_zone".to_string(), "'+00:00'".to_string())]),
+        ..Default::default()
+    }
+}
+
+// Keyset pagination over the (possibly composite) primary key: every chunk starts strictly
+// after the last primary key of the previous chunk, so chunks stay cheap on large tables.
+fn build_snapshot_query(
     keyspace: &KeyspaceName,
     table: &TableName,
     schema: &VitessSchema,
+    filter: Option<&str>,
+    chunk_size: usize,
+    last_pk: Option<Vec<BindVariable>>,
+) -> BoundQuery {
+    let columns = schema
+        .schema
+        .iter()
+        .map(|(field_name, _)| quote_identifier(&field_name.to_string()))
+        .collect::<Vec<String>>()
+        .join(", ");
+    let mut sql = format!(
+        "SELECT {} FROM {}.{}",
+        columns,
+        quote_identifier(&keyspace.to_string()),
+        quote_identifier(&table.to_string())
+    );
+    let mut conditions: Vec<String> = filter
+        .map(|filter| format!("({})", filter))
+        .into_iter()
+        .collect();
+
+    let mut bind_variables = HashMap::new();
+    if schema.primary_keys.is_empty() {
+        if !conditions.is_empty() {
+            sql.push_str(&format!(" WHERE {}", conditions.join(" AND ")));
+        }
+        return BoundQuery {
+            sql,
+            bind_variables,
+        };
+    }
+
+    let pks = schema
+        .primary_keys
+        .iter()
+        .map(|pk| quote_identifier(pk))
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    if let Some(last_pk) = last_pk {
+        let placeholders = last_pk
+            .into_iter()
+            .enumerate()
+            .map(|(i, bind_variable)| {
+                let name = format!("lastpk{}", i);
+                let placeholder = format!(":{}", name);
+                bind_variables.insert(name, bind_variable);
+                placeholder
+            })
+            .collect::<Vec<String>>()
+            .join(", ");
+        conditions.push(format!("({}) > ({})", pks, placeholders));
+    }
+    if !conditions.is_empty() {
+        sql.push_str(&format!(" WHERE {}", conditions.join(" AND ")));
+    }
+    sql.push_str(&format!(" ORDER BY {} LIMIT {}", pks, chunk_size));
+
+    BoundQuery {
+        sql,
+        bind_variables,
+    }
+}
+
+pub(crate) fn quote_identifier(identifier: &str) -> String {
+    format!("`{}`", identifier.replace('`', "``"))
+}
+
+fn varchar_bind_variable(value: &str) -> BindVariable {
+    BindVariable {
+        r#type: Type::Varchar.into(),
+        value: value.as_bytes().to_vec(),
+        values: vec![],
+    }
+}
+
+fn primary_key_bind_variables(
+    primary_key: &PrimaryKeyValues,
+    schema: &VitessSchema,
+) -> Vec<BindVariable> {
+    schema
+        .primary_key_fields()
+        .into_iter()
+        .zip(primary_key.iter())
+        .map(|((_, _, field), value)| BindVariable {
+            r#type: field.r#type,
+            value: value.clone().unwrap_or_default(),
+            values: vec![],
+        })
+        .collect()
+}
+
+async fn process_query_result(
     mut results: Streaming<StreamExecuteResponse>,
-) -> Result<(), VitessSnapshotError> {
+) -> Result<Vec<Row>, tonic::Status> {
+    let mut rows = vec![];
     while let Some(message_result) = results.next().await {
-        let message = message_result.map_err(|e| VitessSnapshotError {
-            keyspace: Box::new(keyspace.clone()),
-            table: Box::new(table.clone()),
-            kind: VitessSnapshotErrorKind::VitessClientCallFailed(e),
-        })?;
+        if let Some(result) = message_result?.result {
+            rows.extend(result.rows);
+        }
+    }
+
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::vitess_grpc::query::Field;
 
-        if let Some(result) = message.result {}
+    fn schema(primary_keys: &[&str]) -> VitessSchema {
+        let fields: Vec<Field> = ["id", "total"]
+            .into_iter()
+            .map(|name| Field {
+                name: name.to_string(),
+                r#type: Type::Int64.into(),
+                ..Default::default()
+            })
+            .collect();
+        VitessSchema {
+            primary_keys: primary_keys.iter().map(|pk| pk.to_string()).collect(),
+            ..VitessSchema::from_field_event("orders".to_string().into(), &fields, None)
+        }
     }
 
-    Ok(())
+    fn row(values: &[&str]) -> Row {
+        Row {
+            lengths: values.iter().map(|value| value.len() as i64).collect(),
+            values: values.concat().into_bytes(),
+        }
+    }
+
+    #[test]
+    fn applies_the_filter_to_chunk_queries() {
+        let keyspace: KeyspaceName = "commerce".to_string().into();
+        let table: TableName = "orders".to_string().into();
+
+        let first = build_snapshot_query(
+            &keyspace,
+            &table,
+            &schema(&["id"]),
+            Some("total > 10 or total < 0"),
+            100,
+            None,
+        );
+        assert_eq!(
+            first.sql,
+            "SELECT `id`, `total` FROM `commerce`.`orders` WHERE (total > 10 or total < 0) ORDER BY `id` LIMIT 100"
+        );
+
+        let next = build_snapshot_query(
+            &keyspace,
+            &table,
+            &schema(&["id"]),
+            Some("total > 10"),
+            100,
+            Some(vec![varchar_bind_variable("5")]),
+        );
+        assert_eq!(
+            next.sql,
+            "SELECT `id`, `total` FROM `commerce`.`orders` WHERE (total > 10) AND (`id`) > (:lastpk0) ORDER BY `id` LIMIT 100"
+        );
+        assert!(next.bind_variables.contains_key("lastpk0"));
+
+        let without_primary_key = build_snapshot_query(
+            &keyspace,
+            &table,
+            &schema(&[]),
+            Some("total > 10"),
+            100,
+            None,
+        );
+        assert_eq!(
+            without_primary_key.sql,
+            "SELECT `id`, `total` FROM `commerce`.`orders` WHERE (total > 10)"
+        );
+    }
+
+    #[test]
+    fn ends_the_table_at_a_short_chunk() {
+        let orders = schema(&["id"]);
+        assert!(!is_last_chunk(100, 100, &orders));
+        assert!(is_last_chunk(99, 100, &orders));
+        assert!(is_last_chunk(0, 100, &orders));
+        assert!(is_last_chunk(0, 1, &orders));
+        // Read in a single query however many rows it returned
+        assert!(is_last_chunk(100, 100, &schema(&[])));
+    }
+
+    #[test]
+    fn rejects_an_empty_chunk_size() {
+        let args = |chunk_size: &str| {
+            <Args as clap::Parser>::try_parse_from([
+                "vitess-replicator",
+                "--keyspace",
+                "commerce",
+                "--vtctld-endpoint",
+                "http://vtctld",
+                "--vtgate-endpoint",
+                "http://vtgate",
+                "--snapshot-chunk-size",
+                chunk_size,
+            ])
+        };
+        assert!(args("0").is_err());
+        assert_eq!(args("1").unwrap().snapshot_chunk_size, 1);
+    }
+
+    #[test]
+    fn reads_timestamps_in_utc() {
+        assert_eq!(
+            snapshot_session().system_variables.get("time_zone"),
+            Some(&"'+00:00'".to_string())
+        );
+    }
+
+    #[test]
+    fn signals_a_chunk_once_every_shard_reported_its_high_watermark() {
+        let (outgoing_signals, incoming_signals) = mpsc::channel();
+        let (stream_started, _) = oneshot::channel();
+        let mut tracker = SnapshotWatermarkTracker::new(
+            "signals".to_string().into(),
+            incoming_signals,
+            stream_started,
+        );
+        let keyspace: KeyspaceName = "commerce".to_string().into();
+        let (emitted_sender, mut emitted) = oneshot::channel();
+        outgoing_signals
+            .send(SnapshotSignal::ChunkStarted {
+                id: "chunk".to_string(),
+                table: "orders".to_string().into(),
+                shards: vec!["-80".to_string(), "80-".to_string()],
+                emitted: emitted_sender,
+            })
+            .unwrap();
+
+        let low = row(&["-80", "chunk:low"]);
+        assert!(tracker.on_signal_row(&keyspace, "-80", 1, &low).is_empty());
+        outgoing_signals
+            .send(SnapshotSignal::ChunkRead {
+                id: "chunk".to_string(),
+                rows: vec![(vec![Some(b"1".to_vec())], row(&["1", "10"]))],
+                schema_version: 2,
+            })
+            .unwrap();
+
+        let high = row(&["-80", "chunk:high"]);
+        assert!(tracker.on_signal_row(&keyspace, "-80", 2, &high).is_empty());
+        assert!(emitted.try_recv().is_err());
+
+        let rows = tracker.on_signal_row(&keyspace, "80-", 3, &high);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].schema_version, 2);
+        assert!(emitted.try_recv().is_ok());
+    }
 }
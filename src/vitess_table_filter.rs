@@ -0,0 +1,503 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Display, Formatter},
+};
+
+use crate::{
+    vitess_grpc::binlogdata::{Filter, Rule},
+    vitess_schema::{TableName, VitessSchema},
+    vitess_snapshot::quote_identifier,
+};
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct TableFilterError {
+    pub table: Box<TableName>,
+    pub kind: TableFilterErrorKind,
+}
+
+impl Display for TableFilterError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            TableFilterErrorKind::UnknownColumn(column) => write!(
+                f,
+                "filter for table `{}` selects unknown column `{}`",
+                self.table, column
+            ),
+            TableFilterErrorKind::MissingPrimaryKey(column) => write!(
+                f,
+                "filter for table `{}` must select primary key column `{}`",
+                self.table, column
+            ),
+        }
+    }
+}
+
+impl Error for TableFilterError {}
+
+#[derive(Debug)]
+pub enum TableFilterErrorKind {
+    UnknownColumn(String),
+    MissingPrimaryKey(String),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum TableSelector {
+    Name(TableName),
+    /// Regular expression matched against the whole table name
+    Pattern(String),
+}
+
+impl TableSelector {
+    /// Table name or `/regexp/` in the form accepted by vtctld `GetSchema`
+    pub(crate) fn schema_pattern(&self) -> String {
+        match self {
+            TableSelector::Name(table) => table.to_string(),
+            TableSelector::Pattern(regex) => format!("/{}/", regex),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TableRule {
+    pub(crate) selector: TableSelector,
+    pub(crate) select: Option<TableSelect>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TableSelect {
+    pub(crate) sql: String,
+    /// Selected columns in order, `None` when every column is selected
+    pub(crate) columns: Option<Vec<String>>,
+    /// Condition after `WHERE`, the snapshot applies it to its chunk queries as well
+    pub(crate) filter: Option<String>,
+}
+
+/// First table whose `WHERE` uses `in_keyrange`, which vstream filters understand but the plain
+/// MySQL queries of the snapshot don't
+pub(crate) fn find_vstream_only_filter(rules: &[TableRule]) -> Option<&TableName> {
+    rules.iter().find_map(|rule| {
+        let filter = rule.select.as_ref()?.filter.as_ref()?;
+        match &rule.selector {
+            TableSelector::Name(table) if filter.to_ascii_lowercase().contains("in_keyrange") => {
+                Some(table)
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Parses `table`, `/regex/` or a glob like `orders_*`, optionally followed by
+/// `=SELECT col1, col2 FROM table WHERE ...` for exact table names.
+pub(crate) fn parse_table_rule(value: &str) -> Result<TableRule, String> {
+    let (table, select) = split_table_rule(value)?;
+
+    let selector = if table.len() > 1 && table.starts_with('/') && table.ends_with('/') {
+        TableSelector::Pattern(format!("^(?:{})$", &table[1..table.len() - 1]))
+    } else if table.contains(['*', '?']) {
+        TableSelector::Pattern(glob_to_regex(table))
+    } else {
+        TableSelector::Name(table.to_string().into())
+    };
+
+    let select = match (select, &selector) {
+        (None, _) => None,
+        (Some(select), TableSelector::Name(table)) => Some(parse_select(table, select)?),
+        (Some(_), TableSelector::Pattern(_)) => {
+            return Err(format!(
+                "`{}` is a pattern, select filters are only supported for exact table names",
+                table
+            ));
+        }
+    };
+
+    Ok(TableRule { selector, select })
+}
+
+// The select starts at the first `=` after the table, a regex may contain `=` itself
+fn split_table_rule(value: &str) -> Result<(&str, Option<&str>), String> {
+    let value = value.trim();
+    let table_end = match value.strip_prefix('/') {
+        Some(regex) => {
+            let mut escaped = false;
+            let closing_slash = regex.char_indices().find_map(|(i, c)| match c {
+                _ if escaped => {
+                    escaped = false;
+                    None
+                }
+                '\\' => {
+                    escaped = true;
+                    None
+                }
+                '/' => Some(i),
+                _ => None,
+            });
+            match closing_slash {
+                Some(closing_slash) => closing_slash + 2,
+                None => return Err(format!("regex `{}` is missing its closing `/`", value)),
+            }
+        }
+        None => 0,
+    };
+
+    Ok(match value[table_end..].find('=') {
+        Some(equals) => (
+            value[..table_end + equals].trim(),
+            Some(value[table_end + equals + 1..].trim()),
+        ),
+        None => (value, None),
+    })
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c if "\\.+()|[]{}^$".contains(c) => {
+                regex.push('\\');
+                regex.push(c);
+            }
+            c => regex.push(c),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+fn parse_select(table: &TableName, select: &str) -> Result<TableSelect, String> {
+    let words = sql_words(select);
+    let invalid = || {
+        format!(
+            "expected `SELECT columns FROM {}` but got `{}`",
+            table, select
+        )
+    };
+    let Some((select_start, select_keyword)) = words.first() else {
+        return Err(invalid());
+    };
+    if !select_keyword.eq_ignore_ascii_case("select") {
+        return Err(invalid());
+    }
+    let from = words
+        .iter()
+        .position(|(_, word)| word.eq_ignore_ascii_case("from"))
+        .ok_or_else(invalid)?;
+    let (from_start, _) = words[from];
+
+    let from_table = words
+        .get(from + 1)
+        .map(|(_, word)| unquote_identifier(word))
+        .unwrap_or_default();
+    if from_table != table.to_string() {
+        return Err(format!(
+            "select filter for `{}` must read from `{}` but reads from `{}`",
+            table, table, from_table
+        ));
+    }
+
+    let filter = match words.get(from + 2) {
+        None => None,
+        Some((where_start, word)) if word.eq_ignore_ascii_case("where") => {
+            Some(select[where_start + word.len()..].trim().to_string())
+        }
+        Some((_, word)) => {
+            return Err(format!(
+                "only a `WHERE` clause can follow the table but got `{}`",
+                word
+            ));
+        }
+    };
+
+    let column_list = select[select_start + select_keyword.len()..from_start].trim();
+    let columns = if column_list == "*" {
+        None
+    } else {
+        let columns: Vec<String> = column_list.split(',').map(unquote_identifier).collect();
+        if let Some(expression) = columns
+            .iter()
+            .find(|c| c.is_empty() || c.contains(|ch: char| ch.is_whitespace() || ch == '('))
+        {
+            return Err(format!(
+                "only plain column names can be selected but got `{}`",
+                expression
+            ));
+        }
+        Some(columns)
+    };
+
+    Ok(TableSelect {
+        sql: select.to_string(),
+        columns,
+        filter,
+    })
+}
+
+// Words separated by any whitespace with their byte offset, so keywords may be separated by
+// newlines or tabs
+fn sql_words(sql: &str) -> Vec<(usize, &str)> {
+    let mut words = vec![];
+    let mut word_start = None;
+    for (i, c) in sql.char_indices() {
+        match (c.is_whitespace(), word_start) {
+            (true, Some(start)) => {
+                words.push((start, &sql[start..i]));
+                word_start = None;
+            }
+            (false, None) => word_start = Some(i),
+            _ => {}
+        }
+    }
+    if let Some(start) = word_start {
+        words.push((start, &sql[start..]));
+    }
+    words
+}
+
+fn unquote_identifier(identifier: &str) -> String {
+    identifier.trim().trim_matches('`').replace("``", "`")
+}
+
+/// Builds one rule per replicated table name so vtgate only streams rows we have a schema for.
+/// Patterns are passed to vtgate as `/regex/` rules, so tables created after startup that match
+/// are streamed as well, with the schema of their FIELD event.
+pub(crate) fn build_table_filter(
+    rules: &[TableRule],
+    schemas: &HashMap<TableName, VitessSchema>,
+    extra_tables: &[TableName],
+) -> Filter {
+    let rule_for_table = |table: &TableName| {
+        let sql = find_table_select(rules, table)
+            .map(|select| select.sql.clone())
+            .unwrap_or_else(|| format!("select * from {}", quote_identifier(&table.to_string())));
+        Rule {
+            r#match: table.to_string(),
+            filter: sql,
+            ..Default::default()
+        }
+    };
+    let is_named = |table: &&TableName| {
+        rules
+            .iter()
+            .any(|rule| matches!(&rule.selector, TableSelector::Name(name) if name == *table))
+    };
+    // Vtgate applies the first rule that matches a table, names go before the patterns
+    let pattern_rules = rules.iter().filter_map(|rule| match &rule.selector {
+        TableSelector::Pattern(_) => Some(Rule {
+            r#match: rule.selector.schema_pattern(),
+            ..Default::default()
+        }),
+        TableSelector::Name(_) => None,
+    });
+
+    Filter {
+        rules: schemas
+            .keys()
+            .filter(is_named)
+            .chain(extra_tables.iter())
+            .map(rule_for_table)
+            .chain(pattern_rules)
+            .collect(),
+        ..Default::default()
+    }
+}
+
+/// Narrows each schema down to the columns its select filter streams, in select order.
+pub(crate) fn project_schemas(
+    rules: &[TableRule],
+    schemas: HashMap<TableName, VitessSchema>,
+) -> Result<HashMap<TableName, VitessSchema>, TableFilterError> {
+    schemas
+        .into_iter()
+        .map(|(table, schema)| {
+            let Some(columns) = find_table_select(rules, &table).and_then(|s| s.columns.as_ref())
+            else {
+                return Ok((table, schema));
+            };
+
+            let error = |kind| TableFilterError {
+                table: Box::new(table.clone()),
+                kind,
+            };
+            if let Some(pk) = schema.primary_keys.iter().find(|pk| !columns.contains(pk)) {
+                return Err(error(TableFilterErrorKind::MissingPrimaryKey(pk.clone())));
+            }
+
+            let fields = columns
+                .iter()
+                .map(|column| {
+                    schema
+                        .schema
+                        .iter()
+                        .find(|(field_name, _)| field_name.to_string() == *column)
+                        .cloned()
+                        .ok_or_else(|| error(TableFilterErrorKind::UnknownColumn(column.clone())))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok((
+                table,
+                VitessSchema {
+                    schema: fields,
+                    ..schema
+                },
+            ))
+        })
+        .collect()
+}
+
+fn find_table_select<'a>(rules: &'a [TableRule], table: &TableName) -> Option<&'a TableSelect> {
+    rules.iter().find_map(|rule| match &rule.selector {
+        TableSelector::Name(name) if name == table => rule.select.as_ref(),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(name: &str) -> TableName {
+        name.to_string().into()
+    }
+
+    #[test]
+    fn parses_exact_table_names() {
+        let rule = parse_table_rule(" orders ").unwrap();
+        assert!(matches!(rule.selector, TableSelector::Name(name) if name == table("orders")));
+        assert!(rule.select.is_none());
+    }
+
+    #[test]
+    fn parses_regex_containing_equals_signs() {
+        let rule = parse_table_rule("/a=b|c/").unwrap();
+        assert!(matches!(&rule.selector, TableSelector::Pattern(regex) if regex == "^(?:a=b|c)$"));
+        assert!(rule.select.is_none());
+        assert_eq!(rule.selector.schema_pattern(), "/^(?:a=b|c)$/");
+    }
+
+    #[test]
+    fn parses_regex_with_escaped_slash() {
+        let rule = parse_table_rule(r"/a\/b/").unwrap();
+        assert!(matches!(&rule.selector, TableSelector::Pattern(regex) if regex == r"^(?:a\/b)$"));
+    }
+
+    #[test]
+    fn rejects_unterminated_regex() {
+        assert!(parse_table_rule("/orders_.*").is_err());
+    }
+
+    #[test]
+    fn rejects_select_on_patterns() {
+        assert!(parse_table_rule("/orders_.*/=select * from orders").is_err());
+        assert!(parse_table_rule("orders_*=select * from orders").is_err());
+    }
+
+    #[test]
+    fn parses_glob() {
+        let rule = parse_table_rule("orders_?_*").unwrap();
+        assert!(
+            matches!(&rule.selector, TableSelector::Pattern(regex) if regex == "^orders_._.*$")
+        );
+    }
+
+    #[test]
+    fn escapes_regex_characters_in_globs() {
+        assert_eq!(glob_to_regex("a.b*"), r"^a\.b.*$");
+        assert_eq!(glob_to_regex("t$(x)+?"), r"^t\$\(x\)\+.$");
+        assert_eq!(glob_to_regex("plain"), "^plain$");
+    }
+
+    #[test]
+    fn parses_select_with_columns_and_where() {
+        let rule =
+            parse_table_rule("orders=SELECT id, `total` FROM `orders` WHERE total > 10").unwrap();
+        let select = rule.select.unwrap();
+        assert_eq!(
+            select.columns,
+            Some(vec!["id".to_string(), "total".to_string()])
+        );
+        assert_eq!(select.filter.as_deref(), Some("total > 10"));
+        assert_eq!(
+            select.sql,
+            "SELECT id, `total` FROM `orders` WHERE total > 10"
+        );
+    }
+
+    #[test]
+    fn parses_select_separated_by_newlines_and_tabs() {
+        let select = parse_select(
+            &table("orders"),
+            "select\tid,\n total\nfrom\torders\nwhere\n\tid = 1",
+        )
+        .unwrap();
+        assert_eq!(
+            select.columns,
+            Some(vec!["id".to_string(), "total".to_string()])
+        );
+        assert_eq!(select.filter.as_deref(), Some("id = 1"));
+    }
+
+    #[test]
+    fn parses_select_star_without_where() {
+        let select = parse_select(&table("orders"), "select * from orders").unwrap();
+        assert_eq!(select.columns, None);
+        assert_eq!(select.filter, None);
+    }
+
+    #[test]
+    fn rejects_invalid_selects() {
+        let orders = table("orders");
+        assert!(parse_select(&orders, "delete from orders").is_err());
+        assert!(parse_select(&orders, "select id orders").is_err());
+        assert!(parse_select(&orders, "select id from customers").is_err());
+        assert!(parse_select(&orders, "select count(id) from orders").is_err());
+        assert!(parse_select(&orders, "select id, from orders").is_err());
+        assert!(parse_select(&orders, "select id from orders limit 10").is_err());
+    }
+
+    #[test]
+    fn finds_keyrange_filters() {
+        let rules = vec![
+            parse_table_rule("orders=select id from orders where id > 10").unwrap(),
+            parse_table_rule("customers=select id from customers where IN_KEYRANGE('-80')")
+                .unwrap(),
+        ];
+        assert_eq!(find_vstream_only_filter(&rules), Some(&table("customers")));
+        assert_eq!(find_vstream_only_filter(&rules[..1]), None);
+    }
+
+    #[test]
+    fn passes_patterns_through_to_vtgate() {
+        let rules = vec![
+            parse_table_rule("orders=select id from orders").unwrap(),
+            parse_table_rule("audit_*").unwrap(),
+        ];
+        let schema = |name: &str| VitessSchema {
+            table: table(name),
+            schema: vec![],
+            primary_keys: vec![],
+        };
+        let schemas = HashMap::from([
+            (table("orders"), schema("orders")),
+            (table("audit_2024"), schema("audit_2024")),
+        ]);
+
+        let filter = build_table_filter(&rules, &schemas, &[table("signals")]);
+        let rules: Vec<(&str, &str)> = filter
+            .rules
+            .iter()
+            .map(|rule| (rule.r#match.as_str(), rule.filter.as_str()))
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                ("orders", "select id from orders"),
+                ("signals", "select * from `signals`"),
+                ("/^audit_.*$/", ""),
+            ]
+        );
+    }
+}
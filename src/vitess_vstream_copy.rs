@@ -0,0 +1,81 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    vitess_grpc::binlogdata::{LastPkEvent, TableLastPk, VGtid},
+    vitess_schema::TableName,
+};
+
+// Vtgate reports copy progress by advancing the `table_p_ks` of each shard in the VGTID that
+// closes a copied batch, and drops a table from it once that table is fully copied. Rows of a
+// transaction whose table last pk moved are copied rows, everything else is regular replication
+// (including the catch up transactions vttablet streams between copy batches). Since the
+// `table_p_ks` are part of every checkpointed VGtid, resuming from a checkpoint picks the copy
+// back up from the last delivered batch.
+pub(crate) struct VStreamCopyTracker {
+    table_last_pks: HashMap<String, Vec<TableLastPk>>,
+    copied_tables: HashSet<(String, TableName)>,
+}
+
+impl VStreamCopyTracker {
+    pub(crate) fn new(vgtid: &VGtid) -> VStreamCopyTracker {
+        VStreamCopyTracker {
+            table_last_pks: vgtid
+                .shard_gtids
+                .iter()
+                .map(|shard_gtid| (shard_gtid.shard.clone(), shard_gtid.table_p_ks.clone()))
+                .collect(),
+            copied_tables: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn on_last_pk(&mut self, shard: &str, last_pk_event: &LastPkEvent) {
+        if let Some(table_last_pk) = &last_pk_event.table_last_p_k {
+            self.copied_tables
+                .insert((shard.to_string(), table_last_pk.table_name.clone().into()));
+        }
+    }
+
+    pub(crate) fn on_vgtid(&mut self, vgtid: &VGtid) {
+        for shard_gtid in vgtid.shard_gtids.iter() {
+            let previous = self
+                .table_last_pks
+                .insert(shard_gtid.shard.clone(), shard_gtid.table_p_ks.clone())
+                .unwrap_or_default();
+            if previous == shard_gtid.table_p_ks {
+                continue;
+            }
+
+            for table_last_pk in previous.iter().chain(shard_gtid.table_p_ks.iter()) {
+                let before = previous
+                    .iter()
+                    .find(|t| t.table_name == table_last_pk.table_name);
+                let after = shard_gtid
+                    .table_p_ks
+                    .iter()
+                    .find(|t| t.table_name == table_last_pk.table_name);
+                if before != after {
+                    if after.is_none() {
+                        log::info!(
+                            "Finished copying {} on shard {}",
+                            table_last_pk.table_name,
+                            shard_gtid.shard
+                        );
+                    }
+                    self.copied_tables.insert((
+                        shard_gtid.shard.clone(),
+                        table_last_pk.table_name.clone().into(),
+                    ));
+                }
+            }
+        }
+    }
+
+    pub(crate) fn is_copied_row(&self, shard: &str, table: &TableName) -> bool {
+        self.copied_tables
+            .contains(&(shard.to_string(), table.clone()))
+    }
+
+    pub(crate) fn end_transaction(&mut self) {
+        self.copied_tables.clear();
+    }
+}
@@ -1,22 +1,35 @@
 use std::{
     error::Error,
     fmt::{self, Display, Formatter},
-    sync::mpsc::{SendError, Sender},
 };
 
+use tokio::{
+    select,
+    sync::{
+        mpsc::{Sender, error::SendError},
+        watch,
+    },
+};
 use tokio_stream::StreamExt;
 use tonic::Streaming;
 
 use crate::{
-    replication_row_event::ReplicationRowEventEnvelope,
+    command_line_args::{Args, CellPreference, InitialLoadMode, SourceTabletType},
+    replication_row_event::ReplicationMessage,
     vitess_grpc::{
-        binlogdata::VGtid,
+        binlogdata::{Filter, VEventType, VGtid},
+        topodata::TabletType,
         vtctlservice::vtctld_client::VtctldClient,
-        vtgate::{VStreamRequest, VStreamResponse},
+        vtgate::{VStreamFlags, VStreamRequest, VStreamResponse},
         vtgateservice::vitess_client::VitessClient,
     },
     vitess_schema::TableName,
-    vitess_shards::{KeyspaceName, get_current_shard_gtids},
+    vitess_schema_registry::SchemaRegistry,
+    vitess_shards::{JournalTracker, KeyspaceName, get_copy_shard_gtids, get_current_shard_gtids},
+    vitess_snapshot::{SnapshotWatermarkTracker, primary_key_values},
+    vitess_vstream_copy::VStreamCopyTracker,
+    vitess_vstream_reconnect::{ReconnectBackoff, ReconnectConfig, is_retryable_status},
+    vitess_vstream_transaction::TransactionBuffer,
 };
 
 #[derive(Debug)]
@@ -41,6 +54,7 @@ impl Error for VstreamListenerError {
         match &self.kind {
             VstreamListenerErrorKind::VitessClientCallFailed(e) => Some(e),
             VstreamListenerErrorKind::SendFailed(e) => Some(e),
+            VstreamListenerErrorKind::RetriesExhausted(e) => Some(e),
         }
     }
 }
@@ -48,80 +62,471 @@ impl Error for VstreamListenerError {
 #[derive(Debug)]
 pub enum VstreamListenerErrorKind {
     VitessClientCallFailed(tonic::Status),
-    SendFailed(SendError<ReplicationRowEventEnvelope>),
+    SendFailed(SendError<ReplicationMessage>),
+    RetriesExhausted(RetriesExhaustedError),
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct RetriesExhaustedError {
+    pub retries: u32,
+    /// Status of the last attempt, `None` if vtgate closed the stream without an error
+    pub last_status: Option<tonic::Status>,
+}
+
+impl Display for RetriesExhaustedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.last_status {
+            Some(_) => write!(f, "gave up reconnecting after {} retries", self.retries),
+            None => write!(
+                f,
+                "gave up reconnecting after {} retries, vtgate kept closing the stream",
+                self.retries
+            ),
+        }
+    }
+}
+
+impl Error for RetriesExhaustedError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.last_status
+            .as_ref()
+            .map(|e| e as &(dyn Error + 'static))
+    }
+}
+
+pub(crate) async fn get_start_vgtid(
+    vtctld_client: &mut VtctldClient<tonic::transport::Channel>,
+    keyspace: &KeyspaceName,
+    checkpoint: Option<VGtid>,
+    initial_load: InitialLoadMode,
+) -> Result<VGtid, VstreamListenerError> {
+    let vgtid = match checkpoint {
+        Some(vgtid) => {
+            log::info!("Resuming vstream from checkpoint {:?}", vgtid);
+            vgtid
+        }
+        None if initial_load == InitialLoadMode::VstreamCopy => {
+            log::info!("No checkpoint found, copying all tables before streaming");
+            let shard_gtids = get_copy_shard_gtids(vtctld_client, keyspace)
+                .await
+                .map_err(|e| VstreamListenerError {
+                    keyspace: Box::new(keyspace.clone()),
+                    kind: VstreamListenerErrorKind::VitessClientCallFailed(e),
+                })?;
+            VGtid { shard_gtids }
+        }
+        None => {
+            log::info!("No checkpoint found, starting vstream from the current position");
+            let shard_gtids = get_current_shard_gtids(vtctld_client, keyspace)
+                .await
+                .map_err(|e| VstreamListenerError {
+                    keyspace: Box::new(keyspace.clone()),
+                    kind: VstreamListenerErrorKind::VitessClientCallFailed(e),
+                })?;
+            VGtid { shard_gtids }
+        }
+    };
+
+    Ok(vgtid)
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct VStreamSourceConfig {
+    pub(crate) tablet_type: TabletType,
+    pub(crate) flags: VStreamFlags,
+}
+
+impl From<&Args> for VStreamSourceConfig {
+    fn from(args: &Args) -> Self {
+        VStreamSourceConfig {
+            tablet_type: match args.tablet_type {
+                SourceTabletType::Primary => TabletType::Primary,
+                SourceTabletType::Replica => TabletType::Replica,
+                SourceTabletType::Rdonly => TabletType::Rdonly,
+            },
+            flags: VStreamFlags {
+                minimize_skew: args.minimize_skew,
+                heartbeat_interval: args.heartbeat_interval_secs,
+                stop_on_reshard: args.stop_on_reshard,
+                cells: args.cells.clone().unwrap_or_default(),
+                cell_preference: match args.cell_preference {
+                    Some(CellPreference::PreferLocal) => "preferlocal".to_string(),
+                    Some(CellPreference::OnlySpecified) => "onlyspecified".to_string(),
+                    None => String::new(),
+                },
+                stream_keyspace_heartbeats: args.stream_keyspace_heartbeats,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+pub(crate) struct VStreamOptions {
+    pub(crate) filter: Filter,
+    pub(crate) source: VStreamSourceConfig,
+    pub(crate) reconnect: ReconnectConfig,
+    pub(crate) transaction_markers: bool,
+    /// Flips to `true` once the listener should stop reading and let the pipeline drain
+    pub(crate) shutdown: watch::Receiver<bool>,
+}
+
+// State that outlives a single vstream connection
+struct VStreamListener {
+    keyspace: KeyspaceName,
+    options: VStreamOptions,
+    schemas: SchemaRegistry,
+    snapshot_tracker: SnapshotWatermarkTracker,
+    outgoing_row_changes: Sender<ReplicationMessage>,
+    // Reconnects resume from the last transaction that was fully handed off
+    position: VGtid,
+    backoff: ReconnectBackoff,
+    journals: JournalTracker,
+    // Set when a journal moved the stream to other shards and it has to be reopened from there
+    followed_journal: bool,
 }
 
 pub(crate) async fn start_vitess_vstream_listener(
-    mut vtctld_client: VtctldClient<tonic::transport::Channel>,
     mut vtgate_client: VitessClient<tonic::transport::Channel>,
     keyspace: KeyspaceName,
-    outgoing_row_changes: Sender<ReplicationRowEventEnvelope>,
+    vgtid: VGtid,
+    options: VStreamOptions,
+    schemas: SchemaRegistry,
+    snapshot_tracker: SnapshotWatermarkTracker,
+    outgoing_row_changes: Sender<ReplicationMessage>,
 ) -> Result<(), VstreamListenerError> {
-    let shard_gtids = get_current_shard_gtids(&mut vtctld_client, &keyspace)
-        .await
-        .map_err(|e| VstreamListenerError {
-            keyspace: Box::new(keyspace.clone()),
-            kind: VstreamListenerErrorKind::VitessClientCallFailed(e),
-        })?;
-    let request = tonic::Request::new(VStreamRequest {
-        caller_id: None,
-        vgtid: Some(VGtid { shard_gtids }),
-        filter: None,
-        tablet_type: 0,
-        flags: None,
-    });
-
-    let response = vtgate_client
-        .v_stream(request)
-        .await
-        .map_err(|e| VstreamListenerError {
-            keyspace: Box::new(keyspace.clone()),
-            kind: VstreamListenerErrorKind::VitessClientCallFailed(e),
-        })?;
-    let mut stream = response.into_inner();
-    process_stream(&keyspace, &mut stream, outgoing_row_changes).await?;
-
-    Ok(())
+    let mut listener = VStreamListener {
+        keyspace,
+        backoff: ReconnectBackoff::new(options.reconnect.clone()),
+        options,
+        schemas,
+        snapshot_tracker,
+        outgoing_row_changes,
+        position: vgtid,
+        journals: JournalTracker::default(),
+        followed_journal: false,
+    };
+    loop {
+        let request = tonic::Request::new(VStreamRequest {
+            caller_id: None,
+            vgtid: Some(listener.position.clone()),
+            filter: Some(listener.options.filter.clone()),
+            tablet_type: listener.options.source.tablet_type as i32,
+            flags: Some(listener.options.source.flags.clone()),
+        });
+
+        let stream_result = match vtgate_client.v_stream(request).await {
+            Ok(response) => listener.process_stream(&mut response.into_inner()).await,
+            Err(e) => Err(VstreamListenerError {
+                keyspace: Box::new(listener.keyspace.clone()),
+                kind: VstreamListenerErrorKind::VitessClientCallFailed(e),
+            }),
+        };
+
+        if listener.is_shutting_down() {
+            log::info!(
+                "Stopped vstream for keyspace {} at {:?}",
+                listener.keyspace,
+                listener.position
+            );
+            return Ok(());
+        }
+
+        if std::mem::take(&mut listener.followed_journal) {
+            log::info!(
+                "Reopening vstream for keyspace {} from {:?}",
+                listener.keyspace,
+                listener.position
+            );
+            continue;
+        }
+
+        let status = match stream_result {
+            Ok(()) => {
+                log::warn!(
+                    "Vstream for keyspace {} ended, reconnecting...",
+                    listener.keyspace
+                );
+                None
+            }
+            Err(VstreamListenerError {
+                kind: VstreamListenerErrorKind::VitessClientCallFailed(status),
+                ..
+            }) if is_retryable_status(&status) => Some(status),
+            Err(e) => return Err(e),
+        };
+
+        let Some(delay) = listener.backoff.next_delay() else {
+            log::error!(
+                "Giving up on vstream for keyspace {} after {} retries",
+                listener.keyspace,
+                listener.backoff.retries()
+            );
+            // Even a stream that kept closing cleanly is an outage the exit code has to report
+            return Err(VstreamListenerError {
+                kind: VstreamListenerErrorKind::RetriesExhausted(RetriesExhaustedError {
+                    retries: listener.backoff.retries(),
+                    last_status: status,
+                }),
+                keyspace: Box::new(listener.keyspace),
+            });
+        };
+        log::warn!(
+            "Vstream for keyspace {} interrupted ({}), retry {} in {:?} from {:?}",
+            listener.keyspace,
+            status
+                .as_ref()
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| "stream closed".to_string()),
+            listener.backoff.retries(),
+            delay,
+            listener.position
+        );
+        let mut shutdown = listener.options.shutdown.clone();
+        select! {
+            _ = tokio::time::sleep(delay) => (),
+            _ = shutdown.wait_for(|stop| *stop) => {
+                log::info!("Stopped vstream for keyspace {} while reconnecting", listener.keyspace);
+                return Ok(());
+            }
+        }
+    }
 }
 
-async fn process_stream(
-    keyspace: &KeyspaceName,
-    stream: &mut Streaming<VStreamResponse>,
-    outgoing_row_changes: Sender<ReplicationRowEventEnvelope>,
-) -> Result<(), VstreamListenerError> {
-    while let Some(message_result) = stream.next().await {
-        let message = message_result.map_err(|e| VstreamListenerError {
-            keyspace: Box::new(keyspace.clone()),
-            kind: VstreamListenerErrorKind::VitessClientCallFailed(e),
-        })?;
-
-        for event in message.events {
-            if let Some(row_event) = event.row_event {
-                let (keyspace, table): (KeyspaceName, TableName) = row_event.table_name
-                    .split_once(".")
-                    .map(|(keyspace_str, table_name_str)| (
-                        keyspace_str.to_string().into(),
-                        table_name_str.to_string().into())
-                    ).expect(
-                        "Expecting schema table name to be keyspace and table name separated by period",
+impl VStreamListener {
+    fn is_shutting_down(&self) -> bool {
+        *self.options.shutdown.borrow()
+    }
+
+    async fn process_stream(
+        &mut self,
+        stream: &mut Streaming<VStreamResponse>,
+    ) -> Result<(), VstreamListenerError> {
+        let keyspace = &self.keyspace;
+        // Copy progress is carried by the VGtid, so it can be rebuilt whenever the stream
+        // restarts. A transaction cut off by a disconnect is streamed again from the start.
+        let mut copy_tracker = VStreamCopyTracker::new(&self.position);
+        let mut transaction = TransactionBuffer::new(keyspace.clone(), &self.position);
+        let mut shutdown = self.options.shutdown.clone();
+        loop {
+            // Checked first so a busy stream can't hold off the shutdown. The buffered part of an
+            // unfinished transaction is dropped, the checkpoint only covers whole transactions.
+            let message_result = select! {
+                biased;
+                _ = shutdown.wait_for(|stop| *stop) => return Ok(()),
+                message_result = stream.next() => match message_result {
+                    Some(message_result) => message_result,
+                    None => break,
+                },
+            };
+            let message = message_result.map_err(|e| VstreamListenerError {
+                keyspace: Box::new(keyspace.clone()),
+                kind: VstreamListenerErrorKind::VitessClientCallFailed(e),
+            })?;
+            self.backoff.reset();
+            self.snapshot_tracker.on_stream_started();
+
+            for event in message.events {
+                let event_type = event.r#type();
+                match event_type {
+                    VEventType::Begin => transaction.on_begin(),
+                    VEventType::Commit => transaction.on_commit(event.timestamp),
+                    VEventType::Heartbeat => log::debug!("Received heartbeat"),
+                    VEventType::Ddl => {
+                        log::info!("Received DDL on shard {}: {}", event.shard, event.statement);
+                        transaction.push_schema_change(
+                            &event.shard,
+                            event.timestamp,
+                            event.statement.clone(),
+                        );
+                    }
+                    _ => {}
+                }
+
+                // Vstream sends the fields of a table before its first row and after every DDL
+                if let Some(field_event) = &event.field_event {
+                    let (keyspace, table) = split_table_name(&field_event.table_name);
+                    self.schemas.on_field_event(
+                        &keyspace,
+                        &field_event.shard,
+                        &table,
+                        &field_event.fields,
+                        &self.position,
                     );
+                }
+
+                if let Some(row_event) = event.row_event {
+                    let (keyspace, table) = split_table_name(&row_event.table_name);
+
+                    if self.snapshot_tracker.is_signal_table(&table) {
+                        for row in row_event
+                            .row_changes
+                            .iter()
+                            .filter_map(|rc| rc.after.as_ref())
+                        {
+                            for snapshot_row in self.snapshot_tracker.on_signal_row(
+                                &keyspace,
+                                &row_event.shard,
+                                event.timestamp,
+                                row,
+                            ) {
+                                self.outgoing_row_changes
+                                    .send(ReplicationMessage::RowEvent(snapshot_row))
+                                    .await
+                                    .map_err(|e| VstreamListenerError {
+                                        keyspace: Box::new(keyspace.clone()),
+                                        kind: VstreamListenerErrorKind::SendFailed(e),
+                                    })?;
+                            }
+                        }
+                        continue;
+                    }
+
+                    log::info!("Received event for {}.{}", keyspace, table);
+                    let schema = self.schemas.current(&keyspace, &row_event.shard, &table);
+                    for row_change in row_event.row_changes {
+                        if self.snapshot_tracker.is_tracking(&table)
+                            && let Some(schema) = &schema
+                        {
+                            for row in [&row_change.before, &row_change.after]
+                                .into_iter()
+                                .flatten()
+                            {
+                                self.snapshot_tracker
+                                    .record_change(&table, primary_key_values(row, &schema.schema));
+                            }
+                        }
+
+                        transaction.push_row(
+                            table.clone(),
+                            &row_event.shard,
+                            event.timestamp,
+                            schema.as_ref().map(|s| s.version).unwrap_or_default(),
+                            row_change.into(),
+                        );
+                    }
+                }
+
+                if let Some(last_pk_event) = &event.last_p_k_event {
+                    copy_tracker.on_last_pk(&event.shard, last_pk_event);
+                }
+
+                if event_type == VEventType::Journal
+                    && let Some(journal) = &event.journal
+                {
+                    // Table names as the journal lists them, the filter rules may be patterns and
+                    // the signal table is not moved along with the replicated ones
+                    let tables: Vec<String> = self
+                        .schemas
+                        .tables()
+                        .into_iter()
+                        .filter(|table| !self.snapshot_tracker.is_signal_table(table))
+                        .map(|table| table.to_string())
+                        .collect();
+                    if let Some(vgtid) = self.journals.on_journal(
+                        &self.position,
+                        journal,
+                        &event.keyspace,
+                        &event.shard,
+                        &tables,
+                    ) {
+                        log::info!(
+                            "Journal {} moved {:?} to {:?}",
+                            journal.id,
+                            journal.participants,
+                            journal.shard_gtids
+                        );
+                        self.position = vgtid.clone();
+                        self.followed_journal = true;
+                        if self.snapshot_tracker.is_completed() {
+                            self.outgoing_row_changes
+                                .send(ReplicationMessage::Checkpoint(vgtid))
+                                .await
+                                .map_err(|e| VstreamListenerError {
+                                    keyspace: Box::new(keyspace.clone()),
+                                    kind: VstreamListenerErrorKind::SendFailed(e),
+                                })?;
+                        }
+                        // The old shards stop at the journal, nothing after it is streamed
+                        return Ok(());
+                    }
+                }
+
+                if event_type == VEventType::CopyCompleted {
+                    if event.shard.is_empty() {
+                        log::info!("Vstream copy phase completed, streaming changes");
+                    } else {
+                        log::info!(
+                            "Vstream copy phase completed for {}/{}",
+                            event.keyspace,
+                            event.shard
+                        );
+                    }
+                }
+
+                // VGTID events follow the row events of a transaction, so once the sink has
+                // delivered everything before this message it is safe to resume from it.
+                if event_type == VEventType::Vgtid
+                    && let Some(vgtid) = event.vgtid
+                {
+                    copy_tracker.on_vgtid(&vgtid);
+                    let completed = transaction.take_transaction(&vgtid, &mut copy_tracker);
+                    for schema_change in completed.schema_changes {
+                        self.outgoing_row_changes
+                            .send(ReplicationMessage::SchemaChange(schema_change))
+                            .await
+                            .map_err(|e| VstreamListenerError {
+                                keyspace: Box::new(keyspace.clone()),
+                                kind: VstreamListenerErrorKind::SendFailed(e),
+                            })?;
+                    }
+                    for envelope in completed.rows {
+                        self.outgoing_row_changes
+                            .send(ReplicationMessage::RowEvent(envelope))
+                            .await
+                            .map_err(|e| VstreamListenerError {
+                                keyspace: Box::new(keyspace.clone()),
+                                kind: VstreamListenerErrorKind::SendFailed(e),
+                            })?;
+                    }
+                    if self.options.transaction_markers {
+                        for boundary in completed.boundaries {
+                            self.outgoing_row_changes
+                                .send(ReplicationMessage::TransactionCommitted(boundary))
+                                .await
+                                .map_err(|e| VstreamListenerError {
+                                    keyspace: Box::new(keyspace.clone()),
+                                    kind: VstreamListenerErrorKind::SendFailed(e),
+                                })?;
+                        }
+                    }
 
-                log::info!("Received event for {}.{}", keyspace, table);
-                for row_change in row_event.row_changes {
-                    outgoing_row_changes
-                        .send(ReplicationRowEventEnvelope {
-                            keyspace: keyspace.clone(),
-                            table: table.clone(),
-                            event: row_change.into(),
-                        })
-                        .map_err(|e| VstreamListenerError {
-                            keyspace: Box::new(keyspace.clone()),
-                            kind: VstreamListenerErrorKind::SendFailed(e),
-                        })?;
+                    self.position = vgtid.clone();
+                    if self.snapshot_tracker.is_completed() {
+                        self.outgoing_row_changes
+                            .send(ReplicationMessage::Checkpoint(vgtid))
+                            .await
+                            .map_err(|e| VstreamListenerError {
+                                keyspace: Box::new(keyspace.clone()),
+                                kind: VstreamListenerErrorKind::SendFailed(e),
+                            })?;
+                    }
                 }
             }
         }
+
+        Ok(())
     }
+}
 
-    Ok(())
+fn split_table_name(table_name: &str) -> (KeyspaceName, TableName) {
+    table_name
+        .split_once(".")
+        .map(|(keyspace_str, table_name_str)| {
+            (
+                keyspace_str.to_string().into(),
+                table_name_str.to_string().into(),
+            )
+        })
+        .expect("Expecting schema table name to be keyspace and table name separated by period")
 }
@@ -0,0 +1,182 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::command_line_args::Args;
+
+#[derive(Debug, Clone)]
+pub(crate) struct ReconnectConfig {
+    pub(crate) max_retries: Option<u32>,
+    pub(crate) initial_backoff: Duration,
+    pub(crate) max_backoff: Duration,
+}
+
+impl From<&Args> for ReconnectConfig {
+    fn from(args: &Args) -> Self {
+        ReconnectConfig {
+            max_retries: args.reconnect_max_retries,
+            initial_backoff: Duration::from_millis(args.reconnect_initial_backoff_ms),
+            max_backoff: Duration::from_millis(args.reconnect_max_backoff_ms),
+        }
+    }
+}
+
+pub(crate) struct ReconnectBackoff {
+    config: ReconnectConfig,
+    retries: u32,
+}
+
+impl ReconnectBackoff {
+    pub(crate) fn new(config: ReconnectConfig) -> ReconnectBackoff {
+        ReconnectBackoff { config, retries: 0 }
+    }
+
+    pub(crate) fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Called whenever the stream makes progress so the next failure starts from the initial backoff
+    pub(crate) fn reset(&mut self) {
+        self.retries = 0;
+    }
+
+    /// Delay before the next attempt, or `None` once the retries are used up
+    pub(crate) fn next_delay(&mut self) -> Option<Duration> {
+        if self
+            .config
+            .max_retries
+            .is_some_and(|max_retries| self.retries >= max_retries)
+        {
+            return None;
+        }
+        self.retries += 1;
+
+        let backoff = self
+            .config
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(self.retries - 1))
+            .min(self.config.max_backoff);
+        // Keep half of the backoff and randomize the rest so listeners that lost the same vtgate
+        // don't all reconnect at once
+        Some(backoff / 2 + backoff.mul_f64(jitter() / 2.0))
+    }
+}
+
+/// Vtgate restarts, tablet failovers and network errors surface as these codes and go away on
+/// their own, anything else (bad filter, unknown keyspace, auth) needs an operator.
+pub(crate) fn is_retryable_status(status: &tonic::Status) -> bool {
+    matches!(
+        status.code(),
+        tonic::Code::Unavailable
+            | tonic::Code::Unknown
+            | tonic::Code::DeadlineExceeded
+            | tonic::Code::Aborted
+            | tonic::Code::Internal
+            | tonic::Code::ResourceExhausted
+            | tonic::Code::Cancelled
+    )
+}
+
+// Random number in `[0, 1)` without pulling in a rng crate, `RandomState` is seeded randomly
+fn jitter() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default(),
+    );
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff(max_retries: Option<u32>) -> ReconnectBackoff {
+        ReconnectBackoff::new(ReconnectConfig {
+            max_retries,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+        })
+    }
+
+    #[test]
+    fn doubles_the_backoff_up_to_the_cap_with_jitter() {
+        let mut backoff = backoff(None);
+        for expected_ms in [100, 200, 400, 800, 1000, 1000, 1000] {
+            let delay = backoff.next_delay().unwrap();
+            let expected = Duration::from_millis(expected_ms);
+            assert!(
+                delay >= expected / 2 && delay < expected,
+                "{:?} should be within [{:?}, {:?})",
+                delay,
+                expected / 2,
+                expected
+            );
+        }
+        assert_eq!(backoff.retries(), 7);
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let mut backoff = backoff(Some(2));
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_none());
+        assert_eq!(backoff.retries(), 2);
+
+        backoff.reset();
+        assert_eq!(backoff.retries(), 0);
+        let delay = backoff.next_delay().unwrap();
+        assert!(delay < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn zero_max_retries_never_retries() {
+        assert!(backoff(Some(0)).next_delay().is_none());
+    }
+
+    #[test]
+    fn jitter_is_within_unit_interval() {
+        for _ in 0..1000 {
+            let jitter = jitter();
+            assert!((0.0..1.0).contains(&jitter));
+        }
+    }
+
+    #[test]
+    fn retries_transient_statuses_only() {
+        for code in [
+            tonic::Code::Unavailable,
+            tonic::Code::Unknown,
+            tonic::Code::DeadlineExceeded,
+            tonic::Code::Aborted,
+            tonic::Code::Internal,
+            tonic::Code::ResourceExhausted,
+            tonic::Code::Cancelled,
+        ] {
+            assert!(
+                is_retryable_status(&tonic::Status::new(code, "")),
+                "{:?}",
+                code
+            );
+        }
+        for code in [
+            tonic::Code::InvalidArgument,
+            tonic::Code::NotFound,
+            tonic::Code::PermissionDenied,
+            tonic::Code::Unauthenticated,
+            tonic::Code::FailedPrecondition,
+            tonic::Code::Unimplemented,
+        ] {
+            assert!(
+                !is_retryable_status(&tonic::Status::new(code, "")),
+                "{:?}",
+                code
+            );
+        }
+    }
+}
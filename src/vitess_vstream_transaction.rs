@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+
+use crate::{
+    replication_row_event::{
+        ReplicationRowEvent, ReplicationRowEventEnvelope, SchemaChangeEnvelope,
+        TransactionBoundary, TransactionContext,
+    },
+    vitess_grpc::binlogdata::VGtid,
+    vitess_schema::TableName,
+    vitess_schema_registry::SchemaVersion,
+    vitess_shards::KeyspaceName,
+    vitess_vstream_copy::VStreamCopyTracker,
+};
+
+// Vtgate streams whole transactions as BEGIN, FIELD/ROW events, COMMIT and a VGTID holding the
+// position of every shard after the commit, DDLs are followed by a VGTID as well. Events are
+// buffered until that VGTID arrives, which provides the transaction id and whether the rows came
+// from the copy phase.
+pub(crate) struct TransactionBuffer {
+    keyspace: KeyspaceName,
+    // Position before the buffered transaction, its id is what the next VGTID adds to it
+    position: VGtid,
+    rows: Vec<ReplicationRowEventEnvelope>,
+    // Rows buffered per shard, the next row's sequence within its shard's transaction
+    sequences: HashMap<String, u64>,
+    schema_changes: Vec<SchemaChangeEnvelope>,
+    commit_timestamp: Option<i64>,
+}
+
+pub(crate) struct CompletedTransaction {
+    pub(crate) schema_changes: Vec<SchemaChangeEnvelope>,
+    pub(crate) rows: Vec<ReplicationRowEventEnvelope>,
+    pub(crate) boundaries: Vec<TransactionBoundary>,
+}
+
+impl TransactionBuffer {
+    pub(crate) fn new(keyspace: KeyspaceName, position: &VGtid) -> TransactionBuffer {
+        TransactionBuffer {
+            keyspace,
+            position: position.clone(),
+            rows: Vec::new(),
+            sequences: HashMap::new(),
+            schema_changes: Vec::new(),
+            commit_timestamp: None,
+        }
+    }
+
+    pub(crate) fn on_begin(&mut self) {
+        self.commit_timestamp = None;
+    }
+
+    pub(crate) fn on_commit(&mut self, timestamp: i64) {
+        self.commit_timestamp = Some(timestamp);
+    }
+
+    pub(crate) fn push_row(
+        &mut self,
+        table: TableName,
+        shard: &str,
+        timestamp: i64,
+        schema_version: SchemaVersion,
+        event: ReplicationRowEvent,
+    ) {
+        let next_sequence = self.sequences.entry(shard.to_string()).or_default();
+        let sequence = *next_sequence;
+        *next_sequence += 1;
+        self.rows.push(ReplicationRowEventEnvelope {
+            keyspace: self.keyspace.clone(),
+            table,
+            event,
+            transaction: TransactionContext {
+                id: String::new(),
+                shard: shard.to_string(),
+                timestamp,
+                sequence,
+            },
+            schema_version,
+        });
+    }
+
+    pub(crate) fn push_schema_change(&mut self, shard: &str, timestamp: i64, statement: String) {
+        self.schema_changes.push(SchemaChangeEnvelope {
+            keyspace: self.keyspace.clone(),
+            shard: shard.to_string(),
+            position: String::new(),
+            timestamp,
+            statement,
+        });
+    }
+
+    /// Completes the buffered transaction, `vgtid` is the position right after its commit
+    pub(crate) fn take_transaction(
+        &mut self,
+        vgtid: &VGtid,
+        copy_tracker: &mut VStreamCopyTracker,
+    ) -> CompletedTransaction {
+        let mut boundaries: Vec<TransactionBoundary> = Vec::new();
+        let rows = self
+            .rows
+            .drain(..)
+            .map(|mut envelope| {
+                let shard = envelope.transaction.shard.clone();
+                if copy_tracker.is_copied_row(&shard, &envelope.table) {
+                    envelope.event = envelope.event.into_snapshot_read();
+                }
+                envelope.transaction.id = transaction_id(&self.position, vgtid, &shard);
+
+                match boundaries.iter_mut().find(|b| b.shard == shard) {
+                    Some(boundary) => boundary.row_count += 1,
+                    None => boundaries.push(TransactionBoundary {
+                        keyspace: self.keyspace.clone(),
+                        shard,
+                        id: envelope.transaction.id.clone(),
+                        timestamp: self
+                            .commit_timestamp
+                            .unwrap_or(envelope.transaction.timestamp),
+                        row_count: 1,
+                    }),
+                }
+                envelope
+            })
+            .collect();
+        let schema_changes = self
+            .schema_changes
+            .drain(..)
+            .map(|mut schema_change| {
+                schema_change.position = shard_position(vgtid, &schema_change.shard);
+                schema_change
+            })
+            .collect();
+        copy_tracker.end_transaction();
+        self.sequences.clear();
+        self.commit_timestamp = None;
+        self.position = vgtid.clone();
+
+        CompletedTransaction {
+            schema_changes,
+            rows,
+            boundaries,
+        }
+    }
+}
+
+fn shard_position(vgtid: &VGtid, shard: &str) -> String {
+    vgtid
+        .shard_gtids
+        .iter()
+        .find(|shard_gtid| shard_gtid.shard == shard)
+        .map(|shard_gtid| shard_gtid.gtid.clone())
+        .unwrap_or_default()
+}
+
+// The GTID a shard's transaction added to its position, like `uuid:42`. Falls back to the whole
+// position when that can't be told, e.g. for MariaDB or file positions.
+fn transaction_id(previous: &VGtid, current: &VGtid, shard: &str) -> String {
+    let position = shard_position(current, shard);
+    last_added_gtid(&shard_position(previous, shard), &position).unwrap_or(position)
+}
+
+// Positions are `MySQL56/uuid1:1-100:105,uuid2:1-7`, transactions only ever add the next number
+// of the current primary's uuid, so the commit is the highest number that is new. Numbers
+// skipped in between belong to transactions the filter didn't stream.
+fn last_added_gtid(previous: &str, current: &str) -> Option<String> {
+    // Starting from `current` or a copy there is no previous set, which is only ambiguous after
+    // a failover left several uuids in the set
+    let previous = latest_gtids(previous).unwrap_or_default();
+    let mut added = latest_gtids(current)?.into_iter().filter(|(uuid, number)| {
+        previous.iter().all(|(previous_uuid, previous_number)| {
+            previous_uuid != uuid || previous_number < number
+        })
+    });
+    let (uuid, number) = added.next()?;
+    if added.next().is_some() {
+        return None;
+    }
+    Some(format!("{}:{}", uuid, number))
+}
+
+// Highest transaction number of every uuid in a mysql gtid set, intervals are in ascending order
+fn latest_gtids(position: &str) -> Option<Vec<(&str, u64)>> {
+    let gtid_set = position.strip_prefix("MySQL56/")?;
+    gtid_set
+        .split(',')
+        .map(|gtids| {
+            let (uuid, intervals) = gtids.trim().split_once(':')?;
+            let number = intervals.rsplit([':', '-']).next()?.parse().ok()?;
+            Some((uuid, number))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vitess_grpc::query::Row;
+
+    const UUID1: &str = "3e11fa47-71ca-11e1-9e33-c80aa9429562";
+    const UUID2: &str = "9b4e2c71-0f6a-11ef-8a3c-0242ac120002";
+
+    #[test]
+    fn takes_the_gtid_the_commit_added() {
+        assert_eq!(
+            last_added_gtid(
+                &format!("MySQL56/{}:1-100", UUID1),
+                &format!("MySQL56/{}:1-101", UUID1)
+            ),
+            Some(format!("{}:101", UUID1))
+        );
+    }
+
+    #[test]
+    fn skips_transactions_that_were_not_streamed() {
+        assert_eq!(
+            last_added_gtid(
+                &format!("MySQL56/{}:1-100,{}:1-7", UUID1, UUID2),
+                &format!("MySQL56/{}:1-100,{}:1-12", UUID1, UUID2)
+            ),
+            Some(format!("{}:12", UUID2))
+        );
+    }
+
+    #[test]
+    fn reads_the_last_interval() {
+        assert_eq!(
+            last_added_gtid(
+                &format!("MySQL56/{}:1-100:105", UUID1),
+                &format!("MySQL56/{}:1-100:105-106", UUID1)
+            ),
+            Some(format!("{}:106", UUID1))
+        );
+    }
+
+    #[test]
+    fn uses_the_only_uuid_without_a_previous_position() {
+        assert_eq!(
+            last_added_gtid("current", &format!("MySQL56/{}:1-5", UUID1)),
+            Some(format!("{}:5", UUID1))
+        );
+        assert_eq!(
+            last_added_gtid("current", &format!("MySQL56/{}:1-5,{}:1-3", UUID1, UUID2)),
+            None
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_whole_position() {
+        assert_eq!(last_added_gtid("", "FilePos/binlog.000001:154"), None);
+
+        let position = |gtid: &str| VGtid {
+            shard_gtids: vec![crate::vitess_grpc::binlogdata::ShardGtid {
+                keyspace: "commerce".to_string(),
+                shard: "-80".to_string(),
+                gtid: gtid.to_string(),
+                table_p_ks: vec![],
+            }],
+        };
+        assert_eq!(
+            transaction_id(
+                &position("current"),
+                &position("FilePos/binlog.000001:154"),
+                "-80"
+            ),
+            "FilePos/binlog.000001:154"
+        );
+        assert_eq!(
+            transaction_id(
+                &position(&format!("MySQL56/{}:1-5", UUID1)),
+                &position(&format!("MySQL56/{}:1-6", UUID1)),
+                "-80"
+            ),
+            format!("{}:6", UUID1)
+        );
+    }
+
+    #[test]
+    fn numbers_rows_per_shard() {
+        let mut buffer = TransactionBuffer::new("commerce".to_string().into(), &VGtid::default());
+        let mut copy_tracker = VStreamCopyTracker::new(&VGtid::default());
+        let push = |buffer: &mut TransactionBuffer, shard| {
+            buffer.push_row(
+                "orders".to_string().into(),
+                shard,
+                0,
+                0,
+                ReplicationRowEvent::Insert(Row::default()),
+            )
+        };
+        let sequences = |transaction: CompletedTransaction| {
+            transaction
+                .rows
+                .iter()
+                .map(|row| (row.transaction.shard.clone(), row.transaction.sequence))
+                .collect::<Vec<_>>()
+        };
+
+        push(&mut buffer, "-80");
+        push(&mut buffer, "80-");
+        push(&mut buffer, "-80");
+        assert_eq!(
+            sequences(buffer.take_transaction(&VGtid::default(), &mut copy_tracker)),
+            vec![
+                ("-80".to_string(), 0),
+                ("80-".to_string(), 0),
+                ("-80".to_string(), 1)
+            ]
+        );
+
+        // The next transaction starts over
+        push(&mut buffer, "-80");
+        assert_eq!(
+            sequences(buffer.take_transaction(&VGtid::default(), &mut copy_tracker)),
+            vec![("-80".to_string(), 0)]
+        );
+    }
+}